@@ -1,69 +1,474 @@
+use std::fmt;
 use std::fs;
-use regex::Regex;
+use std::ops::Range;
 
-fn find_and_compute_multiplications(input: &str) -> i32 {
-    let mut result = 0;
-    let mut enable = true;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sums {
+    // Every contribution, ignoring enable state (part 1)
+    pub unconditional: i64,
+    // Only contributions while enabled (part 2)
+    pub enabled: i64,
+}
 
-    // Compile regex patterns
-    let mul_regex = Regex::new(r"mul\((\d+),(\d+)\)").unwrap();
-    let enable_regex = Regex::new(r"do\(\)").unwrap();
-    let disable_regex = Regex::new(r"don't\(\)").unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub enabled: bool,
+    pub sums: Sums,
+    // How many values went into the part 2 sum, so a trace can tell what contributed
+    enabled_contributions: usize,
+}
 
-    let patterns = [
-        ("disable", &disable_regex),
-        ("enable", &enable_regex),
-        ("mul", &mul_regex),
-    ];
+impl Default for State {
+    fn default() -> State {
+        State { enabled: true, sums: Sums::default(), enabled_contributions: 0 }
+    }
+}
 
-    let mut pos = 0;
+impl State {
+    // Adds a value to the part 1 sum, and to the part 2 sum if enabled. None on overflow.
+    pub fn contribute(&mut self, value: i64) -> Option<()> {
+        self.sums.unconditional = self.sums.unconditional.checked_add(value)?;
+        if self.enabled {
+            self.sums.enabled = self.sums.enabled.checked_add(value)?;
+            self.enabled_contributions += 1;
+        }
+        Some(())
+    }
+}
 
-    while pos < input.len() {
-        // Find the earliest match among all patterns
-        let mut earliest_match: Option<(&str, usize, usize)> = None;
+// What an instruction does to the interpreter, given its arguments. None if the arithmetic
+// overflowed.
+pub type Effect = fn(&mut State, &[i64]) -> Option<()>;
 
-        for (label, regex) in &patterns {
-            if let Some(mat) = regex.find(&input[pos..]) {
-                let start = pos + mat.start();
-                let end = pos + mat.end();
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overflow {
+    pub span: Range<usize>,
+}
 
-                if earliest_match.is_none() || start < earliest_match.unwrap().1 {
-                    earliest_match = Some((label, start, end));
-                }
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Arithmetic overflow in instruction at bytes {}..{}", self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+#[derive(Debug, Clone)]
+pub struct InstructionDef {
+    pub name: String,
+    // Number of comma separated integer arguments between the parentheses
+    pub arity: usize,
+    pub effect: Effect,
+}
+
+// The instructions the lexer recognises. The same name may be defined with different arities,
+// e.g. `mul(a,b)` and `mul(a,b,c)`.
+#[derive(Debug, Clone, Default)]
+pub struct InstructionSet {
+    defs: Vec<InstructionDef>,
+}
+
+impl InstructionSet {
+    // `mul(a,b)`, `do()` and `don't()`, as in the puzzle
+    pub fn standard() -> InstructionSet {
+        let mut set = InstructionSet::default();
+        set.define("mul", 2, |state, args| state.contribute(args[0].checked_mul(args[1])?));
+        set.define("do", 0, |state, _| {
+            state.enabled = true;
+            Some(())
+        });
+        set.define("don't", 0, |state, _| {
+            state.enabled = false;
+            Some(())
+        });
+        set
+    }
+
+    // The standard set plus the instructions of our variant memory dumps
+    pub fn extended() -> InstructionSet {
+        let mut set = InstructionSet::standard();
+        set.define("mul", 3, |state, args| state.contribute(args[0].checked_mul(args[1])?.checked_mul(args[2])?));
+        set.define("add", 2, |state, args| state.contribute(args[0].checked_add(args[1])?));
+        set.define("sub", 2, |state, args| state.contribute(args[0].checked_sub(args[1])?));
+        set.define("reset", 0, |state, _| {
+            state.sums = Sums::default();
+            Some(())
+        });
+        set.define("toggle", 0, |state, _| {
+            state.enabled = !state.enabled;
+            Some(())
+        });
+        set
+    }
+
+    // Adds an instruction, replacing any existing one with the same name and arity
+    pub fn define(&mut self, name: &str, arity: usize, effect: Effect) {
+        let def = InstructionDef { name: name.to_string(), arity, effect };
+        match self.lookup(name, arity) {
+            Some(opcode) => self.defs[opcode] = def,
+            None => self.defs.push(def),
+        }
+    }
+
+    fn lookup(&self, name: &str, arity: usize) -> Option<usize> {
+        self.defs.iter().position(|def| def.name == name && def.arity == arity)
+    }
+
+    pub fn get(&self, opcode: usize) -> &InstructionDef {
+        &self.defs[opcode]
+    }
+
+    // Each defined name once, in definition order
+    fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for def in &self.defs {
+            if !names.contains(&def.name.as_str()) {
+                names.push(&def.name);
             }
         }
+        names
+    }
+}
 
-        if let Some((label, start, end)) = earliest_match {
-            match label {
-                "enable" => { enable = true; }
-                "disable" => { enable = false; }
-                "mul" => {
-                    if enable {
-                        if let Some(caps) = mul_regex.captures(&input[start..end]) {
-                            let a: i32 = caps[1].parse().unwrap();
-                            let b: i32 = caps[2].parse().unwrap();
-                            result += a * b;
-                        }
-                    }
-                }
-                _ => {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexOptions {
+    // Longest accepted argument; the puzzle allows 1-3 digits. None accepts any length that
+    // fits in an i64.
+    pub max_digits: Option<usize>,
+}
+
+impl Default for LexOptions {
+    fn default() -> LexOptions {
+        LexOptions { max_digits: Some(3) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    // Index of the definition in the instruction set
+    pub opcode: usize,
+    pub args: Vec<i64>,
+}
+
+// An instruction with the byte range of the input it was read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned {
+    pub instruction: Instruction,
+    pub span: Range<usize>,
+}
+
+// Why something that starts like an instruction wasn't accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Whitespace,
+    TooManyDigits(usize),
+    OutOfRange,
+    UnexpectedChar(char),
+    UnexpectedEnd,
+    // The name is known but not with this many arguments
+    WrongArity(usize),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::Whitespace => write!(f, "whitespace inside instruction"),
+            RejectReason::TooManyDigits(digits) => write!(f, "argument has {} digits", digits),
+            RejectReason::OutOfRange => write!(f, "argument out of range"),
+            RejectReason::UnexpectedChar(found) => write!(f, "unexpected {:?}", found),
+            RejectReason::UnexpectedEnd => write!(f, "input ends inside instruction"),
+            RejectReason::WrongArity(count) => write!(f, "no definition with {} arguments", count),
+        }
+    }
+}
+
+// A near miss: an instruction name followed by `(`, but not a valid instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected {
+    pub span: Range<usize>,
+    pub reason: RejectReason,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Lexed {
+    pub tokens: Vec<Spanned>,
+    pub rejected: Vec<Rejected>,
+}
+
+// Reads `(a,b,...)` at `pos`: zero or more numbers separated by commas. Returns the arguments
+// and the position after the closing parenthesis, or why and where reading stopped. A lenient
+// read skips whitespace and ignores the digit limit, to find where a near miss ends.
+fn read_args(input: &str, pos: usize, options: &LexOptions, lenient: bool) -> Result<(Vec<i64>, usize), (RejectReason, usize)> {
+    let bytes = input.as_bytes();
+    let mut pos = pos;
+    let mut args = Vec::new();
+    let mut expect_number = true;
+
+    // Everything read so far is ASCII, so `pos` is always on a char boundary here
+    let unexpected = |pos: usize| match input[pos..].chars().next() {
+        Some(found) => (RejectReason::UnexpectedChar(found), pos),
+        None => (RejectReason::UnexpectedEnd, pos),
+    };
+
+    let skip_whitespace = |pos: &mut usize| -> Result<(), (RejectReason, usize)> {
+        if bytes.get(*pos).is_some_and(|byte| byte.is_ascii_whitespace()) {
+            if !lenient {
+                return Err((RejectReason::Whitespace, *pos));
+            }
+            *pos += bytes[*pos..].iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+        }
+        Ok(())
+    };
+
+    skip_whitespace(&mut pos)?;
+    if bytes.get(pos) != Some(&b'(') {
+        return Err(unexpected(pos));
+    }
+    pos += 1;
+    skip_whitespace(&mut pos)?;
+    if bytes.get(pos) == Some(&b')') {
+        return Ok((args, pos + 1));
+    }
+
+    loop {
+        skip_whitespace(&mut pos)?;
+        if expect_number {
+            let digits = bytes[pos..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+            if digits == 0 {
+                return Err(unexpected(pos));
+            }
+            if !lenient && options.max_digits.is_some_and(|max| digits > max) {
+                return Err((RejectReason::TooManyDigits(digits), pos));
+            }
+            // Digits are ASCII, so this slice is valid UTF-8
+            let text = std::str::from_utf8(&bytes[pos..pos + digits]).unwrap();
+            match text.parse() {
+                Ok(arg) => args.push(arg),
+                Err(_) if lenient => args.push(0),
+                Err(_) => return Err((RejectReason::OutOfRange, pos)),
             }
-            // Move the position to the end of the current match
-            pos = end;
+            pos += digits;
+            expect_number = false;
         } else {
-            // No match found, move to the next character
+            match bytes.get(pos) {
+                Some(b',') => expect_number = true,
+                Some(b')') => return Ok((args, pos + 1)),
+                _ => return Err(unexpected(pos)),
+            }
             pos += 1;
         }
     }
+}
+
+// Scans the corrupted memory once, left to right. Anything that isn't a complete instruction
+// of the set is skipped a byte at a time; after an instruction scanning resumes right behind it.
+// A name followed by `(` (possibly after whitespace) that doesn't form a valid instruction is
+// reported as a near miss.
+pub fn tokenize(input: &str, set: &InstructionSet, options: &LexOptions) -> Lexed {
+    let bytes = input.as_bytes();
+    let names = set.names();
+    let mut lexed = Lexed::default();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let mut found = None;
+        let mut near_miss = None;
+
+        for name in names.iter().filter(|name| bytes[pos..].starts_with(name.as_bytes())) {
+            let after_name = pos + name.len();
+            match read_args(input, after_name, options, false) {
+                Ok((args, end)) => match set.lookup(name, args.len()) {
+                    Some(opcode) => {
+                        found = Some((Instruction { opcode, args }, end));
+                        break;
+                    }
+                    None => {
+                        near_miss.get_or_insert(Rejected { span: pos..end, reason: RejectReason::WrongArity(args.len()) });
+                    }
+                },
+                Err((reason, stopped)) => {
+                    let opens = bytes[after_name..].iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'(');
+                    if opens {
+                        // Include the offending char, all of it if it's multi-byte
+                        let end = match read_args(input, after_name, options, true) {
+                            Ok((_, end)) => end,
+                            Err(_) => stopped + input[stopped..].chars().next().map_or(0, char::len_utf8),
+                        };
+                        near_miss.get_or_insert(Rejected { span: pos..end, reason });
+                    }
+                }
+            }
+        }
+
+        match found {
+            Some((instruction, end)) => {
+                lexed.tokens.push(Spanned { instruction, span: pos..end });
+                pos = end;
+            }
+            None => {
+                lexed.rejected.extend(near_miss);
+                pos += 1;
+            }
+        }
+    }
+    lexed
+}
+
+// One executed instruction and the interpreter state right after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub span: Range<usize>,
+    // Enable flag the instruction ran with
+    pub enabled: bool,
+    // Whether it added to the part 2 sum
+    pub contributed: bool,
+    pub sums: Sums,
+}
+
+pub fn interpret_traced(tokens: &[Spanned], set: &InstructionSet) -> Result<(State, Vec<TraceStep>), Overflow> {
+    let mut state = State::default();
+    let mut steps = Vec::new();
+    for spanned in tokens {
+        let instruction = &spanned.instruction;
+        let before = state;
+        (set.get(instruction.opcode).effect)(&mut state, &instruction.args)
+            .ok_or_else(|| Overflow { span: spanned.span.clone() })?;
+        steps.push(TraceStep {
+            span: spanned.span.clone(),
+            enabled: before.enabled,
+            contributed: state.enabled_contributions > before.enabled_contributions,
+            sums: state.sums,
+        });
+    }
+    Ok((state, steps))
+}
+
+const CONTRIBUTED: &str = "\x1b[32m";
+const SKIPPED: &str = "\x1b[33m";
+const NEAR_MISS: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+// The input with instructions coloured: green if they added to the part 2 sum, yellow for
+// everything else that ran (do/don't, disabled multiplications), red for near misses
+pub fn highlight(input: &str, steps: &[TraceStep], rejected: &[Rejected]) -> String {
+    let mut marks: Vec<(Range<usize>, &str)> = steps
+        .iter()
+        .map(|step| (step.span.clone(), if step.contributed { CONTRIBUTED } else { SKIPPED }))
+        .collect();
+    // Near misses can overlap each other and instructions; only colour the ones that don't
+    for near_miss in rejected {
+        if !marks.iter().any(|(span, _)| span.start < near_miss.span.end && near_miss.span.start < span.end) {
+            marks.push((near_miss.span.clone(), NEAR_MISS));
+        }
+    }
+    marks.sort_by_key(|(span, _)| span.start);
 
-    result
+    let mut out = String::new();
+    let mut pos = 0;
+    for (span, colour) in marks {
+        out.push_str(&input[pos..span.start]);
+        out.push_str(colour);
+        out.push_str(&input[span.clone()]);
+        out.push_str(RESET);
+        pos = span.end;
+    }
+    out.push_str(&input[pos..]);
+    out
 }
 
-pub fn process(input_file_path : &str) {
+pub fn process(input_file_path : &str, set: &InstructionSet, options: &LexOptions, show_highlight: bool) -> Result<(), Overflow> {
     let string = fs::read_to_string(input_file_path)
         .expect("Something went wrong reading the file");
 
-    let result = find_and_compute_multiplications(&string);
+    let lexed = tokenize(&string, set, options);
+    let (state, steps) = interpret_traced(&lexed.tokens, set)?;
+
+    for step in &steps {
+        trace!("{:>8}  {:<24} enabled: {:<5}  contributed: {:<5}  total: {}  enabled total: {}",
+               step.span.start, &string[step.span.clone()], step.enabled, step.contributed,
+               step.sums.unconditional, step.sums.enabled);
+    }
+    if show_highlight {
+        print!("{}", highlight(&string, &steps, &lexed.rejected));
+    }
+
+    println!("Sum of all results: {}", state.sums.unconditional);
+    println!("Sum of enabled results: {}", state.sums.enabled);
 
-    println!("{}", result);
-}
\ No newline at end of file
+    info!("Rejected {} near-miss instruction(s)", lexed.rejected.len());
+    for rejected in &lexed.rejected {
+        verbose!("  {}", describe_rejected(&string, rejected));
+    }
+    Ok(())
+}
+
+// One line of the near-miss report
+pub fn describe_rejected(input: &str, rejected: &Rejected) -> String {
+    format!("{:?} at byte {}: {}", &input[rejected.span.clone()], rejected.span.start, rejected.reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(input: &str) -> Lexed {
+        tokenize(input, &InstructionSet::standard(), &LexOptions::default())
+    }
+
+    #[test]
+    fn near_miss_ending_in_multibyte_char_is_listed() {
+        let input = "mul(2,4)mul(é)";
+        let lexed = lex(input);
+        assert_eq!(lexed.tokens.len(), 1);
+        assert_eq!(lexed.rejected, vec![Rejected { span: 8..14, reason: RejectReason::UnexpectedChar('é') }]);
+        assert_eq!(describe_rejected(input, &lexed.rejected[0]), "\"mul(é\" at byte 8: unexpected 'é'");
+    }
+
+    #[test]
+    fn near_misses_in_non_ascii_memory() {
+        let input = "ümul(3,€)mul(1,2)日本mul(4✓mul(5,5)";
+        let lexed = lex(input);
+        let reasons: Vec<RejectReason> = lexed.rejected.iter().map(|rejected| rejected.reason).collect();
+        assert_eq!(reasons, vec![RejectReason::UnexpectedChar('€'), RejectReason::UnexpectedChar('✓')]);
+        for rejected in &lexed.rejected {
+            assert!(input.is_char_boundary(rejected.span.end));
+            assert!(describe_rejected(input, rejected).contains("unexpected"));
+        }
+
+        let (state, _) = interpret_traced(&lexed.tokens, &InstructionSet::standard()).unwrap();
+        assert_eq!(state.sums, Sums { unconditional: 27, enabled: 27 });
+    }
+
+    #[test]
+    fn highlight_handles_non_ascii_memory() {
+        let input = "é mul(2,4)don't()mul(ü)mul(3,3)";
+        let set = InstructionSet::standard();
+        let lexed = lex(input);
+        let (_, steps) = interpret_traced(&lexed.tokens, &set).unwrap();
+        let highlighted = highlight(input, &steps, &lexed.rejected);
+
+        let expected = format!("é {}mul(2,4){}{}don't(){}{}mul(ü{}){}mul(3,3){}",
+                               CONTRIBUTED, RESET, SKIPPED, RESET, NEAR_MISS, RESET, SKIPPED, RESET);
+        assert_eq!(highlighted, expected);
+    }
+
+    #[test]
+    fn extended_set_runs_every_instruction() {
+        let input = "mul(2,3,4)add(5,6)toggle()sub(10,3)mul(2,2)toggle()reset()\
+                     mul(1,2,3)add(1,1)don't()mul(3,3)do()sub(3,5)";
+        let run = |set: &InstructionSet| {
+            let lexed = tokenize(input, set, &LexOptions::default());
+            assert!(lexed.rejected.is_empty());
+            interpret_traced(&lexed.tokens, set).map(|(state, _)| state.sums).unwrap()
+        };
+
+        let mut set = InstructionSet::extended();
+        assert_eq!(run(&set), Sums { unconditional: 15, enabled: 6 });
+
+        // Redefining add(a,b) replaces it rather than adding a second definition
+        let defined = set.defs.len();
+        set.define("add", 2, |state, args| state.contribute(args[0].checked_add(args[1])?.checked_mul(10)?));
+        assert_eq!(set.defs.len(), defined);
+        assert_eq!(run(&set), Sums { unconditional: 33, enabled: 24 });
+    }
+}
@@ -1,242 +1,1102 @@
 use std::fs;
-use std::collections::HashSet;
+use std::collections::HashMap;
 
-pub fn process(input_file_path: &str) {
-    let string = fs::read_to_string(input_file_path)
-        .expect("Something went wrong reading the file");
+pub use generator::PuzzleSpec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderStyle {
+    // Matched letters only, everything else shown as `.`
+    MatchedOnly,
+    // The whole grid, each match in its own ANSI colour; cells used by more than one match inverted
+    Colour,
+}
 
-    let mut unique_matches = HashSet::new();
+// What to show besides the totals, and how overlapping matches are counted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReportOptions {
+    pub render: Option<RenderStyle>,
+    pub list_matches: bool,
+    pub overlap: search_direction::OverlapPolicy,
+    pub matching: search_direction::MatchOptions,
+}
+
+const COLOURS: [&str; 6] = ["\x1b[31m", "\x1b[32m", "\x1b[33m", "\x1b[34m", "\x1b[35m", "\x1b[36m"];
+const INVERTED: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
 
-    let rows: Vec<&str> = string.lines().collect();
+pub fn render(grid: &search_direction::Grid, matches: &[search_direction::MatchLocation], style: RenderStyle) -> String {
+    // For every cell, the indices of the matches using it
+    let mut used_by: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for (index, location) in matches.iter().enumerate() {
+        for &cell in &location.cells {
+            used_by.entry(cell).or_default().push(index);
+        }
+    }
 
-    for line_index in 0..rows.len() {
-        for char_index in 0..rows[line_index].len() {
-            if let Some(match_location) = search_direction::x_pattern_mas(
-                &rows,
-                line_index,
-                char_index,
-                "MAS"
-            ) {
-                // Use just the start line and start char to create a unique key
-                unique_matches.insert((match_location.start_line, match_location.start_char));
+    let mut out = String::new();
+    for line_index in 0..grid.height() {
+        for char_index in 0..grid.row_len(line_index) {
+            let letter = grid.get(line_index as isize, char_index as isize).unwrap();
+            let users = used_by.get(&(line_index, char_index));
+            match (style, users) {
+                (RenderStyle::MatchedOnly, Some(_)) => out.push(letter),
+                (RenderStyle::MatchedOnly, None) => out.push('.'),
+                (RenderStyle::Colour, Some(users)) if users.len() > 1 => {
+                    out.push_str(&format!("{}{}{}", INVERTED, letter, RESET));
+                }
+                (RenderStyle::Colour, Some(users)) => {
+                    out.push_str(&format!("{}{}{}", COLOURS[users[0] % COLOURS.len()], letter, RESET));
+                }
+                (RenderStyle::Colour, None) => out.push(letter),
             }
         }
+        out.push('\n');
     }
+    out
+}
+
+fn report(grid: &search_direction::Grid, matches: &[search_direction::MatchLocation], options: &ReportOptions) {
+    if options.list_matches {
+        for location in matches {
+            println!("{}", location);
+        }
+    }
+    if let Some(style) = options.render {
+        print!("{}", render(grid, matches, style));
+    }
+}
 
-    println!("Total unique MAS X pattern matches: {}", unique_matches.len());
+fn load_grid(text: &str) -> search_direction::Grid {
+    let grid = search_direction::Grid::new(text);
+    if grid.is_ragged() {
+        info!("Warning: rows have different lengths, missing cells won't match");
+    }
+    grid
+}
+
+pub fn process(input_file_path: &str, options: &ReportOptions) {
+    let string = fs::read_to_string(input_file_path)
+        .expect("Something went wrong reading the file");
+
+    let grid = load_grid(&string);
+
+    let xmas = "XMAS".parse().expect("Invalid XMAS word");
+    let word_matches = search_direction::apply_policy(search_direction::find_words(&grid, &[xmas], &options.matching), options.overlap);
+    report(&grid, &word_matches.kept, options);
+    println!("Total XMAS matches in all directions: {}", word_matches);
+
+    let x_mas = "M.S/.A./M.S".parse().expect("Invalid X-MAS pattern");
+    let pattern_matches = search_direction::apply_policy(search_direction::find_pattern(&grid, &x_mas, &options.matching), options.overlap);
+    report(&grid, &pattern_matches.kept, options);
+    println!("Total unique MAS X pattern matches: {}", pattern_matches);
+}
+
+// Counts how often each word of a dictionary file (one word per line) appears in the grid.
+// Words may use `?` wildcards and `[...]` character classes.
+pub fn process_dictionary(input_file_path: &str, dictionary_path: &str, options: &ReportOptions) {
+    let string = fs::read_to_string(input_file_path)
+        .expect("Something went wrong reading the file");
+    let dictionary = fs::read_to_string(dictionary_path)
+        .expect("Something went wrong reading the dictionary");
+
+    let grid = load_grid(&string);
+    let words: Vec<&str> = dictionary.lines().map(|word| word.trim()).filter(|word| !word.is_empty()).collect();
+    let search_words: Vec<search_direction::SearchWord> = words
+        .iter()
+        .map(|word| word.parse().expect("Invalid dictionary word"))
+        .collect();
+
+    let found = search_direction::apply_policy_by(
+        search_direction::find_dictionary_words(&grid, &search_words, &options.matching),
+        options.overlap,
+        |(word_index, location)| (*word_index, location),
+    );
+
+    let mut counts = vec![0; words.len()];
+    let mut matches = Vec::new();
+    for (word_index, location) in &found.kept {
+        counts[*word_index] += 1;
+        matches.push(location.clone());
+    }
+    report(&grid, &matches, options);
+
+    for (word, count) in words.iter().zip(&counts) {
+        println!("{}: {}", word, count);
+    }
+    println!("Total dictionary matches: {}", found);
+}
+
+// Counts placements of a shape pattern such as `M.S/.A./M.S` in any rotation or reflection
+pub fn process_pattern(input_file_path: &str, pattern: &str, options: &ReportOptions) {
+    let string = fs::read_to_string(input_file_path)
+        .expect("Something went wrong reading the file");
+    let grid = load_grid(&string);
+
+    let pattern: search_direction::Pattern = pattern.parse().expect("Invalid pattern");
+    let matches = search_direction::apply_policy(search_direction::find_pattern(&grid, &pattern, &options.matching), options.overlap);
+    report(&grid, &matches.kept, options);
+    println!("Total pattern matches: {}", matches);
+}
+
+// Generates a word-search puzzle from a dictionary file (one word per line) and prints the grid
+pub fn process_generate(dictionary_path: &str, spec: &PuzzleSpec) {
+    let dictionary = fs::read_to_string(dictionary_path)
+        .expect("Something went wrong reading the dictionary");
+    let words: Vec<&str> = dictionary.lines().map(|word| word.trim()).filter(|word| !word.is_empty()).collect();
+
+    let puzzle = generator::generate(&words, spec).expect("Failed to generate puzzle");
+    print!("{}", puzzle.text);
+    for (word, count) in words.iter().zip(&puzzle.counts) {
+        verbose!("{}: {}", word, count);
+    }
+    verbose!("Verified after {} fill attempt(s)", puzzle.attempts);
 }
 
 // Module to encapsulate search direction functions
 mod search_direction {
+    use std::collections::HashSet;
     use std::fmt;
+    use std::str::FromStr;
+    use std::collections::HashMap;
+    use aho_corasick::AhoCorasick;
+
+    #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+    pub enum Direction {
+        Forward,
+        Backward,
+        Upwards,
+        Downwards,
+        DownRight,
+        DownLeft,
+        UpRight,
+        UpLeft,
+    }
+
+    impl Direction {
+        pub const ALL: [Direction; 8] = [
+            Direction::Forward,
+            Direction::Backward,
+            Direction::Upwards,
+            Direction::Downwards,
+            Direction::DownRight,
+            Direction::DownLeft,
+            Direction::UpRight,
+            Direction::UpLeft,
+        ];
+
+        // (line step, char step) taken for each letter of the word
+        pub fn offset(&self) -> (isize, isize) {
+            match self {
+                Direction::Forward => (0, 1),
+                Direction::Backward => (0, -1),
+                Direction::Upwards => (-1, 0),
+                Direction::Downwards => (1, 0),
+                Direction::DownRight => (1, 1),
+                Direction::DownLeft => (1, -1),
+                Direction::UpRight => (-1, 1),
+                Direction::UpLeft => (-1, -1),
+            }
+        }
+
+        pub fn opposite(&self) -> Direction {
+            match self {
+                Direction::Forward => Direction::Backward,
+                Direction::Backward => Direction::Forward,
+                Direction::Upwards => Direction::Downwards,
+                Direction::Downwards => Direction::Upwards,
+                Direction::DownRight => Direction::UpLeft,
+                Direction::DownLeft => Direction::UpRight,
+                Direction::UpRight => Direction::DownLeft,
+                Direction::UpLeft => Direction::DownRight,
+            }
+        }
+    }
+
+    impl FromStr for Direction {
+        type Err = String;
+
+        fn from_str(text: &str) -> Result<Direction, String> {
+            Direction::ALL
+                .into_iter()
+                .find(|direction| direction.to_string() == text)
+                .ok_or_else(|| format!("Unknown direction '{}'", text))
+        }
+    }
+
+    impl fmt::Display for Direction {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = match self {
+                Direction::Forward => "forward",
+                Direction::Backward => "backward",
+                Direction::Upwards => "upwards",
+                Direction::Downwards => "downwards",
+                Direction::DownRight => "down_right",
+                Direction::DownLeft => "down_left",
+                Direction::UpRight => "up_right",
+                Direction::UpLeft => "up_left",
+            };
+            write!(f, "{}", name)
+        }
+    }
+
+    // What was found at a location: a word read in one direction, or a shape pattern
+    #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+    pub enum MatchKind {
+        Word(Direction),
+        Pattern(Orientation),
+    }
 
-    #[derive(Hash, Eq, PartialEq, Debug)]
+    #[derive(Hash, Eq, PartialEq, Debug, Clone)]
     pub struct MatchLocation {
         pub(crate) start_line: usize,
         pub(crate) start_char: usize,
-        direction: &'static str,
+        pub(crate) kind: MatchKind,
+        // Every (line, char) cell the match uses
+        pub(crate) cells: Vec<(usize, usize)>,
     }
 
-    // Each search function returns an Option<MatchLocation>
-    pub fn forward(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        let line = rows[line_index];
-        if char_index + match_word.len() > line.len() {
-            return None;
-        }
+    // The puzzle text split once into characters, so any cell is a constant-time lookup and
+    // non-ASCII letters are one cell each. Rows may have different lengths: cells past the end
+    // of a short row don't exist and never match anything.
+    #[derive(Debug, Clone)]
+    pub struct Grid {
+        cells: Vec<Vec<char>>,
+        // Length of the longest row
+        width: usize,
+    }
 
-        let slice = &line[char_index..char_index + match_word.len()];
-        if slice == match_word {
-            Some(MatchLocation {
-                start_line: line_index,
-                start_char: char_index,
-                direction: "forward",
-            })
-        } else {
-            None
+    impl Grid {
+        pub fn new(text: &str) -> Grid {
+            let cells: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+            let width = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+            Grid { cells, width }
         }
-    }
 
-    pub fn backward(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        let line = rows[line_index];
-        if char_index + 1 < match_word.len() {
-            return None;
+        pub fn height(&self) -> usize {
+            self.cells.len()
         }
 
-        let start = char_index + 1 - match_word.len();
-        let slice = &line[start..=char_index];
-        let reversed_match = match_word.chars().rev().collect::<String>();
+        pub fn row_len(&self, line_index: usize) -> usize {
+            self.cells[line_index].len()
+        }
 
-        if slice == reversed_match {
-            Some(MatchLocation {
-                start_line: line_index,
-                start_char: char_index,
-                direction: "backward",
-            })
-        } else {
-            None
+        // True if rows have different lengths
+        pub fn is_ragged(&self) -> bool {
+            self.cells.windows(2).any(|pair| pair[0].len() != pair[1].len())
         }
-    }
 
-    pub fn upwards(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        if line_index + 1 < match_word.len() {
-            return None;
+        pub fn get(&self, line_index: isize, char_index: isize) -> Option<char> {
+            let (line, column) = self.locate(line_index, char_index, false)?;
+            Some(self.cells[line][column])
         }
 
-        for (offset, char) in match_word.chars().enumerate() {
-            if char_index >= rows[line_index - offset].len() ||
-                rows[line_index - offset].chars().nth(char_index) != Some(char) {
+        // The cell at a position, if it exists. With `wrap_around` the grid is a torus: lines wrap
+        // modulo the height and chars modulo the longest row, so cells missing from short rows
+        // still don't exist.
+        pub fn locate(&self, line_index: isize, char_index: isize, wrap_around: bool) -> Option<(usize, usize)> {
+            let (line_index, char_index) = if wrap_around {
+                if self.width == 0 {
+                    return None;
+                }
+                (line_index.rem_euclid(self.height() as isize), char_index.rem_euclid(self.width as isize))
+            } else {
+                (line_index, char_index)
+            };
+            if line_index < 0 || char_index < 0 {
                 return None;
             }
+            let (line, column) = (line_index as usize, char_index as usize);
+            self.cells.get(line)?.get(column)?;
+            Some((line, column))
         }
 
-        Some(MatchLocation {
-            start_line: line_index,
-            start_char: char_index,
-            direction: "upwards",
-        })
+        // Every existing (line, char) cell, row by row
+        pub fn positions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+            self.cells
+                .iter()
+                .enumerate()
+                .flat_map(|(line_index, row)| (0..row.len()).map(move |char_index| (line_index, char_index)))
+        }
     }
 
-    pub fn downwards(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        if line_index + match_word.len() > rows.len() {
-            return None;
+    // How letters are compared against the grid
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct MatchOptions {
+        pub case_insensitive: bool,
+        // Words and patterns may run off one edge and continue on the opposite one, but never
+        // use the same cell twice
+        pub wrap_around: bool,
+    }
+
+    impl MatchOptions {
+        fn same_letter(&self, expected: char, found: char) -> bool {
+            expected == found || (self.case_insensitive && expected.to_lowercase().eq(found.to_lowercase()))
         }
 
-        for (offset, char) in match_word.chars().enumerate() {
-            if char_index >= rows[line_index + offset].len() ||
-                rows[line_index + offset].chars().nth(char_index) != Some(char) {
-                return None;
+        // The letter itself plus, when ignoring case, its single-char lower and upper case forms
+        fn case_variants(&self, found: char) -> Vec<char> {
+            let mut variants = vec![found];
+            if self.case_insensitive {
+                for changed in [found.to_lowercase().collect::<Vec<char>>(), found.to_uppercase().collect()] {
+                    if let [single] = changed[..] {
+                        variants.push(single);
+                    }
+                }
             }
+            variants
         }
+    }
 
-        Some(MatchLocation {
-            start_line: line_index,
-            start_char: char_index,
-            direction: "downwards",
-        })
+    // One position of a search word
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Letter {
+        Exact(char),
+        // `?`
+        Any,
+        // `[abc]`, `[a-z]` or negated `[^abc]`; members are inclusive ranges
+        Class { members: Vec<(char, char)>, negated: bool },
     }
 
-    pub fn down_right(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        if line_index + match_word.len() > rows.len() ||
-            char_index + match_word.len() > rows[line_index].len() {
-            return None;
+    impl Letter {
+        fn matches(&self, found: char, options: &MatchOptions) -> bool {
+            match self {
+                Letter::Exact(expected) => options.same_letter(*expected, found),
+                Letter::Any => true,
+                Letter::Class { members, negated } => {
+                    let in_class = options.case_variants(found).iter().any(|variant| {
+                        members.iter().any(|(low, high)| (*low..=*high).contains(variant))
+                    });
+                    in_class != *negated
+                }
+            }
         }
+    }
 
-        for (offset, char) in match_word.chars().enumerate() {
-            if char_index + offset >= rows[line_index + offset].len() ||
-                rows[line_index + offset].chars().nth(char_index + offset) != Some(char) {
-                return None;
+    // A word to look for: plain letters, `?` for any single letter and `[...]` character
+    // classes. A backslash makes the next char literal, e.g. `\?`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SearchWord {
+        letters: Vec<Letter>,
+    }
+
+    impl FromStr for SearchWord {
+        type Err = String;
+
+        fn from_str(text: &str) -> Result<SearchWord, String> {
+            let mut letters = Vec::new();
+            let mut chars = text.chars();
+            while let Some(ch) = chars.next() {
+                let letter = match ch {
+                    '?' => Letter::Any,
+                    '\\' => Letter::Exact(chars.next().ok_or_else(|| format!("Dangling escape in '{}'", text))?),
+                    '[' => {
+                        let mut class: Vec<char> = Vec::new();
+                        loop {
+                            match chars.next() {
+                                Some(']') => break,
+                                Some(member) => class.push(member),
+                                None => return Err(format!("Unterminated character class in '{}'", text)),
+                            }
+                        }
+                        let negated = class.first() == Some(&'^');
+                        if negated {
+                            class.remove(0);
+                        }
+                        if class.is_empty() {
+                            return Err(format!("Empty character class in '{}'", text));
+                        }
+
+                        let mut members = Vec::new();
+                        let mut index = 0;
+                        while index < class.len() {
+                            if index + 2 < class.len() && class[index + 1] == '-' {
+                                members.push((class[index], class[index + 2]));
+                                index += 3;
+                            } else {
+                                members.push((class[index], class[index]));
+                                index += 1;
+                            }
+                        }
+                        Letter::Class { members, negated }
+                    }
+                    _ => Letter::Exact(ch),
+                };
+                letters.push(letter);
             }
-        }
 
-        Some(MatchLocation {
-            start_line: line_index,
-            start_char: char_index,
-            direction: "down_right",
-        })
+            if letters.is_empty() {
+                return Err("Empty search word".to_string());
+            }
+            Ok(SearchWord { letters })
+        }
     }
 
-    pub fn down_left(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        if line_index + match_word.len() > rows.len() ||
-            char_index + 1 < match_word.len() {
-            return None;
+    impl SearchWord {
+        // A word matched letter for letter, with no wildcards or classes
+        pub fn exact(text: &str) -> SearchWord {
+            SearchWord {
+                letters: text.chars().map(Letter::Exact).collect(),
+            }
+        }
+
+        // The plain text of the word if it has no wildcards or classes
+        fn literal(&self) -> Option<String> {
+            self.letters
+                .iter()
+                .map(|letter| match letter {
+                    Letter::Exact(ch) => Some(*ch),
+                    _ => None,
+                })
+                .collect()
         }
+    }
+
+    // Checks whether `match_word` starts at the given cell and reads in `direction`
+    pub fn search(grid: &Grid, line_index: usize, char_index: usize, match_word: &SearchWord, direction: Direction,
+                  options: &MatchOptions) -> Option<MatchLocation> {
+        let (line_step, char_step) = direction.offset();
+        let mut cells = Vec::new();
 
-        for (offset, char) in match_word.chars().enumerate() {
-            if char_index < offset ||
-                rows[line_index + offset].chars().nth(char_index - offset) != Some(char) {
+        for (offset, letter) in match_word.letters.iter().enumerate() {
+            let line = line_index as isize + line_step * offset as isize;
+            let column = char_index as isize + char_step * offset as isize;
+            let cell = grid.locate(line, column, options.wrap_around)?;
+            if cells.contains(&cell) || !letter.matches(grid.cells[cell.0][cell.1], options) {
                 return None;
             }
+            cells.push(cell);
         }
 
         Some(MatchLocation {
             start_line: line_index,
             start_char: char_index,
-            direction: "down_left",
+            kind: MatchKind::Word(direction),
+            cells,
         })
     }
 
-    pub fn up_right(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        if line_index + 1 < match_word.len() ||
-            char_index + match_word.len() > rows[line_index].len() {
-            return None;
+    // Every occurrence of every word, in all eight directions
+    pub fn find_words(grid: &Grid, words: &[SearchWord], options: &MatchOptions) -> Vec<MatchLocation> {
+        let mut matches = Vec::new();
+        for (line_index, char_index) in grid.positions() {
+            for word in words {
+                for direction in Direction::ALL {
+                    if let Some(location) = search(grid, line_index, char_index, word, direction, options) {
+                        matches.push(location);
+                    }
+                }
+            }
         }
+        matches
+    }
 
-        for (offset, char) in match_word.chars().enumerate() {
-            if char_index + offset >= rows[line_index - offset].len() ||
-                rows[line_index - offset].chars().nth(char_index + offset) != Some(char) {
-                return None;
+    // Every straight line through the grid in the four "forward" directions (rows, columns and
+    // both diagonals), as the (line, char) cells along it. Reading a line backwards covers the
+    // other four directions. Ragged rows simply cut a line short where a cell is missing.
+    fn grid_lines(grid: &Grid) -> Vec<(Direction, Vec<(usize, usize)>)> {
+        let exists = |line: isize, column: isize| grid.get(line, column).is_some();
+
+        let mut lines = Vec::new();
+        for direction in [Direction::Forward, Direction::Downwards, Direction::DownRight, Direction::DownLeft] {
+            let (line_step, char_step) = direction.offset();
+            for (line_index, char_index) in grid.positions() {
+                // Only start where the line can't be extended backwards
+                if exists(line_index as isize - line_step, char_index as isize - char_step) {
+                    continue;
+                }
+                let mut cells = Vec::new();
+                let (mut line, mut column) = (line_index as isize, char_index as isize);
+                while exists(line, column) {
+                    cells.push((line as usize, column as usize));
+                    line += line_step;
+                    column += char_step;
+                }
+                lines.push((direction, cells));
             }
         }
+        lines
+    }
 
-        Some(MatchLocation {
-            start_line: line_index,
-            start_char: char_index,
-            direction: "up_right",
-        })
+    // Finds every word of a dictionary in one pass over each line (and its reverse) using an
+    // Aho-Corasick automaton. Returns the index of the word found with its location. Words with
+    // wildcards or classes, non-ASCII words when ignoring case, and every word when wrapping
+    // around can't go through the automaton and are searched cell by cell instead.
+    pub fn find_dictionary_words(grid: &Grid, words: &[SearchWord], options: &MatchOptions) -> Vec<(usize, MatchLocation)> {
+        let mut literals = Vec::new();
+        let mut literal_indices = Vec::new();
+        let mut matches = Vec::new();
+
+        for (word_index, word) in words.iter().enumerate() {
+            match word.literal() {
+                Some(literal) if !options.wrap_around && (!options.case_insensitive || literal.is_ascii()) => {
+                    literals.push(literal);
+                    literal_indices.push(word_index);
+                }
+                _ => {
+                    let found = find_words(grid, std::slice::from_ref(word), options);
+                    matches.extend(found.into_iter().map(|location| (word_index, location)));
+                }
+            }
+        }
+        if literals.is_empty() {
+            return matches;
+        }
+
+        let automaton = AhoCorasick::builder()
+            .ascii_case_insensitive(options.case_insensitive)
+            .build(&literals)
+            .expect("Failed to build word automaton");
+
+        for (direction, cells) in grid_lines(grid) {
+            let reversed: Vec<(usize, usize)> = cells.iter().rev().copied().collect();
+            for (direction, cells) in [(direction, cells), (direction.opposite(), reversed)] {
+                let text: String = cells.iter().map(|&(line, column)| grid.cells[line][column]).collect();
+                // Matches are reported as byte offsets; map them back to cells
+                let char_starts: HashMap<usize, usize> = text.char_indices()
+                    .enumerate()
+                    .map(|(char_index, (byte_index, _))| (byte_index, char_index))
+                    .collect();
+
+                for found in automaton.find_overlapping_iter(&text) {
+                    let first = char_starts[&found.start()];
+                    let length = text[found.range()].chars().count();
+                    let (start_line, start_char) = cells[first];
+                    matches.push((literal_indices[found.pattern().as_usize()], MatchLocation {
+                        start_line,
+                        start_char,
+                        kind: MatchKind::Word(direction),
+                        cells: cells[first..first + length].to_vec(),
+                    }));
+                }
+            }
+        }
+        matches
+    }
+
+    // Which way a shape pattern was turned: quarter turns clockwise, after an optional left-right mirror
+    #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+    pub struct Orientation {
+        pub rotation: u8,
+        pub reflected: bool,
+    }
+
+    // A small grid of letters where `.` matches anything, written row by row with `/` between
+    // rows, e.g. `M.S/.A./M.S` for the X-MAS cross. Short rows are padded with wildcards.
+    #[derive(Hash, Eq, PartialEq, Debug, Clone)]
+    pub struct Pattern {
+        cells: Vec<Vec<Option<char>>>,
     }
 
-    pub fn up_left(rows: &[&str], line_index: usize, char_index: usize, match_word: &str) -> Option<MatchLocation> {
-        if line_index + 1 < match_word.len() ||
-            char_index + 1 < match_word.len() {
-            return None;
+    impl FromStr for Pattern {
+        type Err = String;
+
+        fn from_str(text: &str) -> Result<Pattern, String> {
+            let mut cells: Vec<Vec<Option<char>>> = text
+                .split('/')
+                .map(|row| row.chars().map(|ch| if ch == '.' { None } else { Some(ch) }).collect())
+                .collect();
+
+            let width = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+            if width == 0 {
+                return Err(format!("Empty pattern '{}'", text));
+            }
+            // All wildcards would match at every cell
+            if cells.iter().flatten().all(|cell| cell.is_none()) {
+                return Err(format!("Pattern '{}' has no letters", text));
+            }
+            for row in &mut cells {
+                row.resize(width, None);
+            }
+            Ok(Pattern { cells })
         }
+    }
 
-        for (offset, char) in match_word.chars().enumerate() {
-            if char_index < offset ||
-                rows[line_index - offset].chars().nth(char_index - offset) != Some(char) {
-                return None;
+    impl Pattern {
+        fn height(&self) -> usize {
+            self.cells.len()
+        }
+
+        fn width(&self) -> usize {
+            self.cells[0].len()
+        }
+
+        fn rotated(&self) -> Pattern {
+            let cells = (0..self.width())
+                .map(|column| (0..self.height()).rev().map(|line| self.cells[line][column]).collect())
+                .collect();
+            Pattern { cells }
+        }
+
+        fn reflected(&self) -> Pattern {
+            let cells = self.cells.iter().map(|row| row.iter().rev().copied().collect()).collect();
+            Pattern { cells }
+        }
+
+        // All distinct rotations and reflections of the pattern
+        pub fn orientations(&self) -> Vec<(Orientation, Pattern)> {
+            let mut variants: Vec<(Orientation, Pattern)> = Vec::new();
+            for reflected in [false, true] {
+                let mut pattern = if reflected { self.reflected() } else { self.clone() };
+                for rotation in 0..4 {
+                    if !variants.iter().any(|(_, existing)| *existing == pattern) {
+                        variants.push((Orientation { rotation, reflected }, pattern.clone()));
+                    }
+                    pattern = pattern.rotated();
+                }
             }
+            variants
         }
 
-        Some(MatchLocation {
-            start_line: line_index,
-            start_char: char_index,
-            direction: "up_left",
-        })
+        // If the pattern matches with its top-left corner at the given cell, the grid cells
+        // holding its letters
+        fn placed_at(&self, grid: &Grid, line_index: usize, char_index: usize, options: &MatchOptions) -> Option<Vec<(usize, usize)>> {
+            let mut cells = Vec::new();
+            for (line_offset, row) in self.cells.iter().enumerate() {
+                for (char_offset, expected) in row.iter().enumerate() {
+                    let Some(expected) = expected else { continue };
+                    let cell = grid.locate(
+                        (line_index + line_offset) as isize,
+                        (char_index + char_offset) as isize,
+                        options.wrap_around,
+                    )?;
+                    if cells.contains(&cell) || !options.same_letter(*expected, grid.cells[cell.0][cell.1]) {
+                        return None;
+                    }
+                    cells.push(cell);
+                }
+            }
+            Some(cells)
+        }
     }
 
-    pub fn x_pattern_mas(rows: &[&str], line_index: usize, char_index: usize, _match_word: &str) -> Option<MatchLocation> {
-        // Check if we have enough room to search diagonally
-        if line_index == 0 || line_index >= rows.len() - 1 ||
-            char_index == 0 || char_index >= rows[line_index].len() - 1 {
-            return None;
+    // Every placement of the pattern in any orientation; locations are the top-left corner
+    // of the placed (turned) pattern
+    pub fn find_pattern(grid: &Grid, pattern: &Pattern, options: &MatchOptions) -> Vec<MatchLocation> {
+        let orientations = pattern.orientations();
+        let mut matches = Vec::new();
+
+        for (line_index, char_index) in grid.positions() {
+            for (orientation, variant) in &orientations {
+                if let Some(cells) = variant.placed_at(grid, line_index, char_index, options) {
+                    matches.push(MatchLocation {
+                        start_line: line_index,
+                        start_char: char_index,
+                        kind: MatchKind::Pattern(*orientation),
+                        cells,
+                    });
+                }
+            }
         }
+        matches
+    }
 
-        // Check if current position is 'A'
-        if rows[line_index].chars().nth(char_index) != Some('A') {
-            return None;
+    // How matches that share cells are counted
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum OverlapPolicy {
+        // Every distinct (start, direction) occurrence counts
+        #[default]
+        EveryOccurrence,
+        // A match may not reuse a letter of an earlier match (first found wins)
+        NoSharedLetters,
+        // Matches covering exactly the same cells count once, so a palindrome read both ways
+        // (or a symmetric pattern in two orientations) is one match
+        PalindromesOnce,
+    }
+
+    impl fmt::Display for OverlapPolicy {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = match self {
+                OverlapPolicy::EveryOccurrence => "every occurrence",
+                OverlapPolicy::NoSharedLetters => "no shared letters",
+                OverlapPolicy::PalindromesOnce => "palindromes once",
+            };
+            write!(f, "{}", name)
         }
+    }
 
-        // Get all diagonal positions
-        let up_left = rows[line_index - 1].chars().nth(char_index - 1);
-        let up_right = rows[line_index - 1].chars().nth(char_index + 1);
-        let down_left = rows[line_index + 1].chars().nth(char_index - 1);
-        let down_right = rows[line_index + 1].chars().nth(char_index + 1);
+    impl FromStr for OverlapPolicy {
+        type Err = String;
 
-        // Check both diagonals - BOTH must spell "MAS"
-        let diagonal1_valid = (up_left == Some('M') && down_right == Some('S')) ||
-            (up_left == Some('S') && down_right == Some('M'));
+        fn from_str(text: &str) -> Result<OverlapPolicy, String> {
+            match text {
+                "every" => Ok(OverlapPolicy::EveryOccurrence),
+                "disjoint" => Ok(OverlapPolicy::NoSharedLetters),
+                "palindromes" => Ok(OverlapPolicy::PalindromesOnce),
+                _ => Err(format!("Unknown overlap policy '{}'", text)),
+            }
+        }
+    }
 
-        let diagonal2_valid = (up_right == Some('M') && down_left == Some('S')) ||
-            (up_right == Some('S') && down_left == Some('M'));
+    // Matches left after applying an overlap policy, along with the policy used
+    #[derive(Debug, Clone)]
+    pub struct Counted<T> {
+        pub policy: OverlapPolicy,
+        pub kept: Vec<T>,
+        pub dropped: usize,
+    }
 
-        // Only return a match if both diagonals spell "MAS"
-        if diagonal1_valid && diagonal2_valid {
-            Some(MatchLocation {
-                start_line: line_index,
-                start_char: char_index,
-                direction: "x_pattern_mas",
-            })
-        } else {
-            None
+    impl<T> fmt::Display for Counted<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} ({}", self.kept.len(), self.policy)?;
+            if self.dropped > 0 {
+                write!(f, ", {} dropped", self.dropped)?;
+            }
+            write!(f, ")")
+        }
+    }
+
+    pub fn apply_policy(matches: Vec<MatchLocation>, policy: OverlapPolicy) -> Counted<MatchLocation> {
+        apply_policy_by(matches, policy, |location| (0, location))
+    }
+
+    // Filters any list of items carrying a match location, keeping the original order. `location`
+    // also gives the word an item is for: occurrences are only ever merged with ones of the same
+    // word, while letters are never shared between any two matches.
+    pub fn apply_policy_by<T>(items: Vec<T>, policy: OverlapPolicy, location: impl Fn(&T) -> (usize, &MatchLocation)) -> Counted<T> {
+        let total = items.len();
+        let kept: Vec<T> = match policy {
+            OverlapPolicy::EveryOccurrence => {
+                let mut seen = HashSet::new();
+                items.into_iter()
+                    .filter(|item| {
+                        let (word, location) = location(item);
+                        seen.insert((word, location.start_line, location.start_char, location.kind))
+                    })
+                    .collect()
+            }
+            OverlapPolicy::NoSharedLetters => {
+                let mut used = HashSet::new();
+                items.into_iter()
+                    .filter(|item| {
+                        let cells = &location(item).1.cells;
+                        if cells.iter().any(|cell| used.contains(cell)) {
+                            return false;
+                        }
+                        used.extend(cells.iter().copied());
+                        true
+                    })
+                    .collect()
+            }
+            OverlapPolicy::PalindromesOnce => {
+                let mut seen = HashSet::new();
+                items.into_iter()
+                    .filter(|item| {
+                        let (word, location) = location(item);
+                        let mut cells = location.cells.clone();
+                        cells.sort();
+                        seen.insert((word, cells))
+                    })
+                    .collect()
+            }
+        };
+
+        Counted {
+            policy,
+            dropped: total - kept.len(),
+            kept,
         }
     }
 
     // Implement Display trait for MatchLocation (optional, but helpful for debugging)
     impl fmt::Display for MatchLocation {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            write!(f, "Match at line {}, char {}, direction: {}",
-                   self.start_line, self.start_char, self.direction)
+            match self.kind {
+                MatchKind::Word(direction) => write!(f, "Match at line {}, char {}, direction: {}",
+                                                     self.start_line, self.start_char, direction),
+                MatchKind::Pattern(orientation) => write!(f, "Match at line {}, char {}, pattern rotated {} quarter turns{}",
+                                                          self.start_line, self.start_char, orientation.rotation,
+                                                          if orientation.reflected { ", reflected" } else { "" }),
+            }
+        }
+    }
+}
+
+// Word-search puzzle generator, checked against the searcher above
+mod generator {
+    use super::search_direction::{self, Direction, Grid, MatchKind, MatchOptions, SearchWord};
+
+    // Letters that aren't part of a placed word yet. Never equal to a word letter.
+    const EMPTY: char = '\0';
+    const FILL_ATTEMPTS: usize = 100;
+
+    // xorshift64: tiny, seedable and plenty for shuffling letters
+    struct Rng {
+        state: u64,
+    }
+
+    impl Rng {
+        fn new(seed: u64) -> Rng {
+            // The all-zero state never changes, so nudge it
+            Rng { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+        }
+
+        fn next(&mut self) -> u64 {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state
+        }
+
+        // Uniform enough in 0..bound for small bounds
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
         }
     }
-}
\ No newline at end of file
+
+    #[derive(Debug, Clone)]
+    pub struct PuzzleSpec {
+        pub height: usize,
+        pub width: usize,
+        pub directions: Vec<Direction>,
+        pub alphabet: Vec<char>,
+        pub seed: u64,
+    }
+
+    impl Default for PuzzleSpec {
+        fn default() -> PuzzleSpec {
+            PuzzleSpec {
+                height: 10,
+                width: 10,
+                directions: Direction::ALL.to_vec(),
+                alphabet: ('A'..='Z').collect(),
+                seed: 1,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Puzzle {
+        // Rows separated by newlines, ready to be written as an input file
+        pub text: String,
+        // How often each word appears in the finished grid, reading only in the allowed directions
+        pub counts: Vec<usize>,
+        pub attempts: usize,
+    }
+
+    fn to_text(cells: &[Vec<char>]) -> String {
+        cells.iter().map(|row| row.iter().collect::<String>() + "\n").collect()
+    }
+
+    // Occurrences of each word in the grid, reading in the given directions only
+    fn count_words(cells: &[Vec<char>], words: &[SearchWord], directions: &[Direction]) -> Vec<usize> {
+        let grid = Grid::new(&to_text(cells));
+        words
+            .iter()
+            .map(|word| {
+                search_direction::find_words(&grid, std::slice::from_ref(word), &MatchOptions::default())
+                    .iter()
+                    .filter(|location| matches!(location.kind, MatchKind::Word(direction) if directions.contains(&direction)))
+                    .count()
+            })
+            .collect()
+    }
+
+    fn place(cells: &mut [Vec<char>], word: &[char], (line_index, char_index, direction): (usize, usize, Direction)) {
+        let (line_step, char_step) = direction.offset();
+        for (offset, letter) in word.iter().enumerate() {
+            let line = (line_index as isize + line_step * offset as isize) as usize;
+            let column = (char_index as isize + char_step * offset as isize) as usize;
+            cells[line][column] = *letter;
+        }
+    }
+
+    // Every (line, char, direction) where the word fits, agreeing with letters already placed
+    fn placements(cells: &[Vec<char>], word: &[char], directions: &[Direction]) -> Vec<(usize, usize, Direction)> {
+        let (height, width) = (cells.len() as isize, cells[0].len() as isize);
+        let mut found = Vec::new();
+        for line_index in 0..height {
+            for char_index in 0..width {
+                for &direction in directions {
+                    let (line_step, char_step) = direction.offset();
+                    let fits = word.iter().enumerate().all(|(offset, letter)| {
+                        let line = line_index + line_step * offset as isize;
+                        let column = char_index + char_step * offset as isize;
+                        (0..height).contains(&line)
+                            && (0..width).contains(&column)
+                            && [EMPTY, *letter].contains(&cells[line as usize][column as usize])
+                    });
+                    if fits {
+                        found.push((line_index as usize, char_index as usize, direction));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    // Places each word once (longest first, overlapping where letters agree) and fills the
+    // rest randomly. A placement is expected to add the occurrences its own letters hold: one,
+    // or more for palindromes, words inside other words and single letters. Placements that
+    // also create occurrences across other words are skipped, and fillings that create extra
+    // occurrences are thrown away and redrawn.
+    pub fn generate(words: &[&str], spec: &PuzzleSpec) -> Result<Puzzle, String> {
+        if spec.height == 0 || spec.width == 0 {
+            return Err("Puzzle must have at least one row and column".to_string());
+        }
+        if spec.directions.is_empty() || spec.alphabet.is_empty() {
+            return Err("Puzzle needs at least one direction and one fill letter".to_string());
+        }
+
+        let mut rng = Rng::new(spec.seed);
+        let mut cells = vec![vec![EMPTY; spec.width]; spec.height];
+        let search_words: Vec<SearchWord> = words.iter().map(|word| SearchWord::exact(word)).collect();
+        let mut intended = vec![0; words.len()];
+
+        let mut order: Vec<usize> = (0..words.len()).collect();
+        order.sort_by_key(|&index| std::cmp::Reverse(words[index].chars().count()));
+        for index in order {
+            let word: Vec<char> = words[index].chars().collect();
+            if word.is_empty() || word.contains(&EMPTY) {
+                return Err(format!("Can't place word '{}'", words[index]));
+            }
+
+            let mut options = placements(&cells, &word, &spec.directions);
+            loop {
+                if options.is_empty() {
+                    return Err(format!("No room left for '{}'", words[index]));
+                }
+                let placement = options.swap_remove(rng.below(options.len()));
+
+                // What the word adds on its own, and what the grid holds once it is placed
+                let mut alone = vec![vec![EMPTY; spec.width]; spec.height];
+                place(&mut alone, &word, placement);
+                let added = count_words(&alone, &search_words, &spec.directions);
+                let expected: Vec<usize> = intended.iter().zip(&added).map(|(before, extra)| before + extra).collect();
+
+                let mut placed = cells.clone();
+                place(&mut placed, &word, placement);
+                if count_words(&placed, &search_words, &spec.directions) == expected {
+                    cells = placed;
+                    intended = expected;
+                    break;
+                }
+            }
+        }
+
+        for attempt in 1..=FILL_ATTEMPTS {
+            let filled: Vec<Vec<char>> = cells
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|&letter| if letter == EMPTY { spec.alphabet[rng.below(spec.alphabet.len())] } else { letter })
+                        .collect()
+                })
+                .collect();
+
+            if count_words(&filled, &search_words, &spec.directions) == intended {
+                return Ok(Puzzle {
+                    text: to_text(&filled),
+                    counts: intended,
+                    attempts: attempt,
+                });
+            }
+        }
+        Err(format!("No filling without extra matches in {} attempts", FILL_ATTEMPTS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::search_direction::*;
+    use super::PuzzleSpec;
+
+    const SAMPLE: &str = "MMMSXXMASM\nMSAMXMSMSA\nAMXSXMAAMM\nMSAMASMSMX\nXMASAMXAMM\n\
+XXAMMXXAMA\nSMSMSASXSS\nSAXAMASAAA\nMAMMMXMMMM\nMXMXAXMASX\n";
+
+    #[test]
+    fn sample_has_18_xmas() {
+        let grid = Grid::new(SAMPLE);
+        let xmas: SearchWord = "XMAS".parse().unwrap();
+        assert_eq!(find_words(&grid, &[xmas], &MatchOptions::default()).len(), 18);
+    }
+
+    #[test]
+    fn sample_has_9_x_mas() {
+        let grid = Grid::new(SAMPLE);
+        let x_mas: Pattern = "M.S/.A./M.S".parse().unwrap();
+        assert_eq!(find_pattern(&grid, &x_mas, &MatchOptions::default()).len(), 9);
+    }
+
+    #[test]
+    fn wrapping_uses_the_longest_row_and_skips_missing_cells() {
+        // The second row is short, so only the top row can wrap all the way round
+        let grid = Grid::new("ASXM\nXM\n");
+        let xmas: SearchWord = "XMAS".parse().unwrap();
+        assert!(find_words(&grid, std::slice::from_ref(&xmas), &MatchOptions::default()).is_empty());
+
+        let options = MatchOptions { wrap_around: true, ..MatchOptions::default() };
+        let matches = find_words(&grid, &[xmas], &options);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].cells, vec![(0, 2), (0, 3), (0, 0), (0, 1)]);
+        assert_eq!(grid.locate(1, 6, true), None);
+        assert_eq!(grid.locate(-1, 5, true), Some((1, 1)));
+    }
+
+    // Matches in a form that can be sorted and compared
+    fn sorted(matches: impl IntoIterator<Item = (usize, MatchLocation)>) -> Vec<String> {
+        let mut described: Vec<String> = matches.into_iter().map(|(word, location)| format!("{} {:?}", word, location)).collect();
+        described.sort();
+        described
+    }
+
+    #[test]
+    fn dictionary_search_matches_word_by_word_search() {
+        let words: Vec<SearchWord> = ["XMAS", "SAM", "xmas", "A", "MM", "X?A", "[AS]M", "ÄB", "äb"]
+            .iter()
+            .map(|word| word.parse().unwrap())
+            .collect();
+        let grids = [SAMPLE, "XMAS\nMA\nSAMXX\n\nAM\nxmasÄ\nÄBäb\n", "A\nMAS\nxM\nsAMäB\nb\n"];
+
+        for text in grids {
+            let grid = Grid::new(text);
+            for case_insensitive in [false, true] {
+                let options = MatchOptions { case_insensitive, ..MatchOptions::default() };
+                let expected = words.iter().enumerate().flat_map(|(index, word)| {
+                    find_words(&grid, std::slice::from_ref(word), &options).into_iter().map(move |location| (index, location))
+                });
+                assert_eq!(sorted(find_dictionary_words(&grid, &words, &options)), sorted(expected),
+                           "grid {:?}, ignore case {}", text, case_insensitive);
+            }
+        }
+    }
+
+    // Occurrences of each word found by the dictionary search, reading only in `directions`
+    fn counts_by_search(text: &str, words: &[&str], directions: &[Direction]) -> Vec<usize> {
+        let search_words: Vec<SearchWord> = words.iter().map(|word| word.parse().unwrap()).collect();
+        let mut counts = vec![0; words.len()];
+        for (word_index, location) in find_dictionary_words(&Grid::new(text), &search_words, &MatchOptions::default()) {
+            if matches!(location.kind, MatchKind::Word(direction) if directions.contains(&direction)) {
+                counts[word_index] += 1;
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn generated_puzzles_agree_with_the_search() {
+        let words = ["XMAS", "MAS", "SAMAS", "ABBA", "MASS"];
+        let direction_sets = [
+            Direction::ALL.to_vec(),
+            vec![Direction::Forward],
+            vec![Direction::Forward, Direction::Backward, Direction::DownRight],
+        ];
+        for directions in direction_sets {
+            for seed in 1..=20 {
+                let spec = PuzzleSpec { height: 8, width: 8, directions: directions.clone(), seed, ..PuzzleSpec::default() };
+                let puzzle = super::generator::generate(&words, &spec).unwrap();
+                assert_eq!(puzzle.text.lines().count(), 8);
+                assert!(puzzle.text.lines().all(|row| row.chars().count() == 8));
+                assert!(puzzle.counts.iter().all(|&count| count > 0), "{:?}", puzzle.counts);
+                assert_eq!(counts_by_search(&puzzle.text, &words, &directions), puzzle.counts,
+                           "seed {} directions {:?}\n{}", seed, directions, puzzle.text);
+            }
+        }
+    }
+
+    #[test]
+    fn generated_counts_only_include_allowed_directions() {
+        let spec = PuzzleSpec { directions: vec![Direction::Forward], alphabet: vec!['B'], ..PuzzleSpec::default() };
+        let puzzle = super::generator::generate(&["A"], &spec).unwrap();
+        assert_eq!(puzzle.counts, vec![1]);
+    }
+}
@@ -1,16 +1,47 @@
 use std::fs;
 use std::str::FromStr;
 use std::fmt;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use crate::output::{self, Verbosity};
+
+// Anything that can name a page: the puzzle's page numbers, but also task names, version tags,
+// file names, ... as long as it can be parsed from text, hashed and ordered
+pub trait Page: Clone + Hash + Eq + Ord + FromStr + fmt::Debug + fmt::Display {}
+
+impl<T: Clone + Hash + Eq + Ord + FromStr + fmt::Debug + fmt::Display> Page for T {}
+
+// Which part of the input a parse error was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Rules,
+    Updates,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Section::Rules => write!(f, "rules"),
+            Section::Updates => write!(f, "updates"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ParseError {
     message: String,
+    // Section, 1-based line and 1-based column, when the error points at a spot in the input
+    location: Option<(Section, usize, usize)>,
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Parse error: {}", self.message)
+        match self.location {
+            Some((section, line, column)) => write!(f, "Parse error in {} section at line {}, column {}: {}",
+                                                    section, line, column, self.message),
+            None => write!(f, "Parse error: {}", self.message),
+        }
     }
 }
 
@@ -19,7 +50,8 @@ impl std::error::Error for ParseError {}
 impl From<std::io::Error> for ParseError {
     fn from(error: std::io::Error) -> Self {
         ParseError {
-            message: error.to_string()
+            message: error.to_string(),
+            location: None,
         }
     }
 }
@@ -28,56 +60,251 @@ impl ParseError {
     fn new(msg: &str) -> ParseError {
         ParseError {
             message: msg.to_string(),
+            location: None,
+        }
+    }
+
+    fn at(msg: &str, section: Section, line: usize, column: usize) -> ParseError {
+        ParseError {
+            message: msg.to_string(),
+            location: Some((section, line, column)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    // The puzzle format: `a|b` rules, a blank line, then comma separated updates
+    Text,
+    // {"rules": [[47, 53], "97|13", ...], "updates": [[75, 47, 61], ...]}
+    Json,
+    // One record per line: `rule,47,53` or `update,75,47,61`
+    Csv,
+}
+
+impl InputFormat {
+    fn from_path(filename: &str) -> InputFormat {
+        let lower = filename.to_lowercase();
+        if lower.ends_with(".json") {
+            InputFormat::Json
+        } else if lower.ends_with(".csv") {
+            InputFormat::Csv
+        } else {
+            InputFormat::Text
         }
     }
 }
 
-pub fn parse_file(filename: &str) -> Result<(Vec<(u32, u32)>, Vec<Vec<u32>>), ParseError> {
-    // Read the file contents into a string and normalize line endings
+type Rules<P> = Vec<(P, P)>;
+type Updates<P> = Vec<Vec<P>>;
+
+// Picks the format from the file extension
+pub fn parse_file<P: Page>(filename: &str) -> Result<(Rules<P>, Updates<P>), ParseError> {
+    parse_file_as(filename, InputFormat::from_path(filename))
+}
+
+pub fn parse_file_as<P: Page>(filename: &str, format: InputFormat) -> Result<(Rules<P>, Updates<P>), ParseError> {
     let contents = fs::read_to_string(filename)?;
-    let contents = contents.replace("\r\n", "\n");
+    parse_str(&contents, format)
+}
 
-    // Split into sections, being more lenient with empty lines
-    let sections: Vec<&str> = contents
-        .split("\n\n")
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .collect();
+pub fn parse_str<P: Page>(contents: &str, format: InputFormat) -> Result<(Rules<P>, Updates<P>), ParseError> {
+    match format {
+        InputFormat::Text => parse_text(contents),
+        InputFormat::Json => parse_json(contents),
+        InputFormat::Csv => parse_csv(contents),
+    }
+}
 
-    if sections.len() != 2 {
-        println!("Debug - Number of sections found: {}", sections.len());
-        println!("Debug - Content:\n{}", contents);
+// Drops a `#` comment and returns what's left of the line. A `#` only starts a comment at the
+// beginning of the line or after whitespace, so page names like `C#` or `#12` survive inside a
+// line (`47|#12`, `C#,#12`). A line starting with `#` is always a comment, and a trailing note
+// needs a space before it: `47|53 # note`, not `47|53# note`.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = None;
+    for (index, ch) in line.char_indices() {
+        if ch == '#' && previous.is_none_or(char::is_whitespace) {
+            return &line[..index];
+        }
+        previous = Some(ch);
+    }
+    line
+}
+
+// Parses one page, reporting the column where the (trimmed) field starts
+fn parse_page<P: Page>(field: &str, field_offset: usize, section: Section, line_number: usize) -> Result<P, ParseError> {
+    let trimmed = field.trim();
+    let column = field_offset + (field.len() - field.trim_start().len()) + 1;
+    if trimmed.is_empty() {
+        return Err(ParseError::at("Expected a page", section, line_number, column));
+    }
+    P::from_str(trimmed)
+        .map_err(|_| ParseError::at(&format!("Failed to parse page '{}'", trimmed), section, line_number, column))
+}
+
+// Splits on commas, allowing one trailing comma. Yields each field with its byte offset in the line.
+fn split_fields(line: &str) -> Vec<(usize, &str)> {
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    for field in line.split(',') {
+        fields.push((offset, field));
+        offset += field.len() + 1;
+    }
+    if fields.len() > 1 && fields.last().is_some_and(|(_, field)| field.trim().is_empty()) {
+        fields.pop();
+    }
+    fields
+}
+
+fn parse_text<P: Page>(contents: &str) -> Result<(Rules<P>, Updates<P>), ParseError> {
+    let mut pairs = Vec::new();
+    let mut number_lists = Vec::new();
+    let mut section = Section::Rules;
+
+    for (index, raw_line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        // Blank (or whitespace only) lines separate the rules from the updates
+        if raw_line.trim().is_empty() {
+            if section == Section::Rules && !pairs.is_empty() {
+                section = Section::Updates;
+            }
+            continue;
+        }
+
+        // Comment-only lines are skipped without ending the section
+        let line = strip_comment(raw_line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match section {
+            Section::Rules => {
+                let Some(bar) = line.find('|') else {
+                    let column = line.len() - line.trim_start().len() + 1;
+                    return Err(ParseError::at("Invalid pair format, expected 'a|b'", section, line_number, column));
+                };
+                let x = parse_page(&line[..bar], 0, section, line_number)?;
+                let y = parse_page(&line[bar + 1..], bar + 1, section, line_number)?;
+                pairs.push((x, y));
+            }
+            Section::Updates => {
+                let list = split_fields(line)
+                    .into_iter()
+                    .map(|(offset, field)| parse_page(field, offset, section, line_number))
+                    .collect::<Result<Vec<_>, ParseError>>()?;
+                // Can't happen with split_fields today, but an empty update has no middle page
+                if list.is_empty() {
+                    return Err(ParseError::at("Update has no pages", section, line_number, 1));
+                }
+                number_lists.push(list);
+            }
+        }
+    }
+
+    if section != Section::Updates {
         return Err(ParseError::new("File format incorrect: expected two sections separated by blank line"));
     }
 
-    // Parse the first section (pairs)
-    let pairs: Vec<(u32, u32)> = sections[0]
-        .lines()
-        .filter(|line| !line.trim().is_empty())
-        .map(|line| {
-            let parts: Vec<&str> = line.split('|').collect();
-            if parts.len() != 2 {
-                return Err(ParseError::new("Invalid pair format"));
+    Ok((pairs, number_lists))
+}
+
+fn parse_csv<P: Page>(contents: &str) -> Result<(Rules<P>, Updates<P>), ParseError> {
+    let mut pairs = Vec::new();
+    let mut number_lists = Vec::new();
+
+    for (index, raw_line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comment(raw_line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = split_fields(line);
+        let (_, kind) = fields[0];
+        match kind.trim() {
+            "rule" => {
+                if fields.len() != 3 {
+                    return Err(ParseError::at("Expected 'rule,a,b'", Section::Rules, line_number, 1));
+                }
+                let x = parse_page(fields[1].1, fields[1].0, Section::Rules, line_number)?;
+                let y = parse_page(fields[2].1, fields[2].0, Section::Rules, line_number)?;
+                pairs.push((x, y));
+            }
+            "update" => {
+                let list = fields[1..]
+                    .iter()
+                    .map(|&(offset, field)| parse_page(field, offset, Section::Updates, line_number))
+                    .collect::<Result<Vec<_>, ParseError>>()?;
+                if list.is_empty() {
+                    let column = kind.len() + 1;
+                    return Err(ParseError::at("Update has no pages", Section::Updates, line_number, column));
+                }
+                number_lists.push(list);
             }
+            other => {
+                let section = if number_lists.is_empty() { Section::Rules } else { Section::Updates };
+                let column = kind.len() - kind.trim_start().len() + 1;
+                return Err(ParseError::at(&format!("Unknown record type '{}'", other), section, line_number, column));
+            }
+        }
+    }
 
-            let x = u32::from_str(parts[0].trim())
-                .map_err(|_| ParseError::new("Failed to parse number"))?;
-            let y = u32::from_str(parts[1].trim())
-                .map_err(|_| ParseError::new("Failed to parse number"))?;
-            Ok((x, y))
+    Ok((pairs, number_lists))
+}
+
+fn parse_json<P: Page>(contents: &str) -> Result<(Rules<P>, Updates<P>), ParseError> {
+    let value: serde_json::Value = serde_json::from_str(contents)
+        .map_err(|e| ParseError::new(&format!("Invalid JSON at line {}, column {}: {}", e.line(), e.column(), e)))?;
+
+    // Pages may be written as JSON numbers or strings
+    let page = |value: &serde_json::Value, what: &str| -> Result<P, ParseError> {
+        let text = match value {
+            serde_json::Value::String(text) => text.clone(),
+            serde_json::Value::Number(num) => num.to_string(),
+            _ => return Err(ParseError::new(&format!("Expected a page in {}, found {}", what, value))),
+        };
+        P::from_str(&text)
+            .map_err(|_| ParseError::new(&format!("Failed to parse page '{}' in {}", text, what)))
+    };
+
+    let rules = value.get("rules")
+        .and_then(|rules| rules.as_array())
+        .ok_or_else(|| ParseError::new("Expected a \"rules\" array"))?;
+    let pairs = rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            let what = format!("rules[{}]", i);
+            match rule {
+                serde_json::Value::Array(pair) if pair.len() == 2 => Ok((page(&pair[0], &what)?, page(&pair[1], &what)?)),
+                serde_json::Value::String(text) => {
+                    let (x, y) = text.split_once('|')
+                        .ok_or_else(|| ParseError::new(&format!("Invalid pair format in {}", what)))?;
+                    let parse = |name: &str| P::from_str(name.trim())
+                        .map_err(|_| ParseError::new(&format!("Failed to parse page in {}", what)));
+                    Ok((parse(x)?, parse(y)?))
+                }
+                _ => Err(ParseError::new(&format!("Invalid pair format in {}", what))),
+            }
         })
         .collect::<Result<Vec<_>, ParseError>>()?;
 
-    // Parse the second section (number lists)
-    let number_lists: Vec<Vec<u32>> = sections[1]
-        .lines()
-        .filter(|line| !line.trim().is_empty())
-        .map(|line| {
-            line.split(',')
-                .map(|num| {
-                    u32::from_str(num.trim())
-                        .map_err(|_| ParseError::new("Failed to parse number"))
-                })
+    let updates = value.get("updates")
+        .and_then(|updates| updates.as_array())
+        .ok_or_else(|| ParseError::new("Expected an \"updates\" array"))?;
+    let number_lists = updates
+        .iter()
+        .enumerate()
+        .map(|(i, update)| {
+            let what = format!("updates[{}]", i);
+            let pages = update.as_array()
+                .ok_or_else(|| ParseError::new(&format!("Expected an array in {}", what)))?;
+            if pages.is_empty() {
+                return Err(ParseError::new(&format!("Update has no pages in {}", what)));
+            }
+            pages
+                .iter()
+                .map(|num| page(num, &what))
                 .collect::<Result<Vec<_>, ParseError>>()
         })
         .collect::<Result<Vec<_>, ParseError>>()?;
@@ -85,86 +312,390 @@ pub fn parse_file(filename: &str) -> Result<(Vec<(u32, u32)>, Vec<Vec<u32>>), Pa
     Ok((pairs, number_lists))
 }
 
-fn validate_lists(pairs: &[(u32, u32)], number_lists: &[Vec<u32>]) -> Vec<bool> {
-    let mut dependencies: HashMap<u32, HashSet<u32>> = HashMap::new();
+// A broken rule `before|after`: `after` shows up earlier in the list than `before`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleViolation<P> {
+    pub before: P,
+    pub before_index: usize,
+    pub after: P,
+    pub after_index: usize,
+}
 
-    for &(before, after) in pairs {
-        dependencies
-            .entry(before)
-            .or_insert_with(HashSet::new)
-            .insert(after);
+impl<P: fmt::Display> fmt::Display for RuleViolation<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "page {} at index {} must come after {} at index {} (rule {}|{})",
+               self.after, self.after_index, self.before, self.before_index, self.before, self.after)
     }
+}
 
+fn validate_lists<P: Page>(rules: &CompiledRules<P>, number_lists: &[Vec<P>]) -> Vec<Vec<RuleViolation<P>>> {
     number_lists
         .iter()
-        .map(|list| validate_single_list(list, &dependencies))
+        .map(|list| rules.violations(list))
         .collect()
 }
 
-fn validate_single_list(list: &[u32], dependencies: &HashMap<u32, HashSet<u32>>) -> bool {
-    for (i, &current_num) in list.iter().enumerate() {
-        if let Some(must_come_after) = dependencies.get(&current_num) {
-            let numbers_before: HashSet<_> = list[..i].iter().collect();
+// Returns every violated rule, given a lookup for "must `a` come before `b`"; an empty result
+// means the list is valid. Shared by the compiled and the editable rules.
+fn list_violations<P: Page>(list: &[P], must_precede: impl Fn(&P, &P) -> bool) -> Vec<RuleViolation<P>> {
+    let mut violations = Vec::new();
+    for (i, current_num) in list.iter().enumerate() {
+        for (j, earlier) in list[..i].iter().enumerate() {
+            if must_precede(current_num, earlier) {
+                violations.push(RuleViolation {
+                    before: current_num.clone(),
+                    before_index: i,
+                    after: earlier.clone(),
+                    after_index: j,
+                });
+            }
+        }
+    }
+    violations
+}
 
-            if must_come_after.iter().any(|after| numbers_before.contains(after)) {
-                return false;
+// Adding this rule would close a cycle: `cycle` runs from `rule.0` through `rule.1` back to `rule.0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleError<P> {
+    pub rule: (P, P),
+    pub cycle: Vec<P>,
+}
+
+impl<P: fmt::Display> fmt::Display for CycleError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path: Vec<String> = self.cycle.iter().map(|page| page.to_string()).collect();
+        write!(f, "rule {}|{} introduces a cycle: {}", self.rule.0, self.rule.1, path.join(" -> "))
+    }
+}
+
+// Rules that can be edited at runtime while a set of tracked updates is kept validated.
+// Each update keeps a count of the rules it breaks, so adding or removing a rule only touches
+// the updates containing its pages.
+#[derive(Debug, Clone)]
+pub struct RuleSet<P> {
+    dependencies: HashMap<P, HashSet<P>>,
+    updates: Vec<Vec<P>>,
+    positions: Vec<HashMap<P, usize>>,
+    violation_counts: Vec<usize>,
+    // Which tracked updates contain each page
+    page_updates: HashMap<P, Vec<usize>>,
+}
+
+impl<P: Page> RuleSet<P> {
+    pub fn new(pairs: &[(P, P)]) -> RuleSet<P> {
+        RuleSet {
+            dependencies: dependencies_map(pairs),
+            updates: Vec::new(),
+            positions: Vec::new(),
+            violation_counts: Vec::new(),
+            page_updates: HashMap::new(),
+        }
+    }
+
+    // Starts validating an update; returns its id
+    pub fn track(&mut self, update: Vec<P>) -> usize {
+        let id = self.updates.len();
+        for page in &update {
+            self.page_updates.entry(page.clone()).or_default().push(id);
+        }
+        self.positions.push(update.iter().enumerate().map(|(i, page)| (page.clone(), i)).collect());
+        self.violation_counts.push(self.violations_of(&update).len());
+        self.updates.push(update);
+        id
+    }
+
+    pub fn is_valid(&self, id: usize) -> bool {
+        self.violation_counts[id] == 0
+    }
+
+    pub fn violations(&self, id: usize) -> Vec<RuleViolation<P>> {
+        self.violations_of(&self.updates[id])
+    }
+
+    fn violations_of(&self, list: &[P]) -> Vec<RuleViolation<P>> {
+        list_violations(list, |before, after| self.contains(before, after))
+    }
+
+    pub fn contains(&self, before: &P, after: &P) -> bool {
+        self.dependencies.get(before).is_some_and(|afters| afters.contains(after))
+    }
+
+    // Tracked updates that have `after` in front of `before`
+    fn updates_breaking(&self, before: &P, after: &P) -> Vec<usize> {
+        self.page_updates
+            .get(before)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|&id| {
+                let positions = &self.positions[id];
+                positions.get(after).is_some_and(|after_index| *after_index < positions[before])
+            })
+            .collect()
+    }
+
+    // Path of rules leading from `from` to `to`, if any
+    fn rule_path(&self, from: &P, to: &P) -> Option<Vec<P>> {
+        let mut previous: HashMap<&P, &P> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(current) = queue.pop_front() {
+            if current == to {
+                let mut path = vec![to.clone()];
+                let mut step = to;
+                while step != from {
+                    step = previous[step];
+                    path.push(step.clone());
+                }
+                path.reverse();
+                return Some(path);
             }
+            for next in self.dependencies.get(current).into_iter().flatten() {
+                if next != from && !previous.contains_key(next) {
+                    previous.insert(next, current);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    // Adds `before|after` unless it would close a cycle. Returns the tracked updates that
+    // became invalid because of it.
+    pub fn add_rule(&mut self, before: P, after: P) -> Result<Vec<usize>, CycleError<P>> {
+        if self.contains(&before, &after) {
+            return Ok(Vec::new());
+        }
+        if let Some(path) = self.rule_path(&after, &before) {
+            let mut cycle = vec![before.clone()];
+            cycle.extend(path);
+            return Err(CycleError { rule: (before, after), cycle });
+        }
+
+        let mut newly_invalid = Vec::new();
+        for id in self.updates_breaking(&before, &after) {
+            if self.violation_counts[id] == 0 {
+                newly_invalid.push(id);
+            }
+            self.violation_counts[id] += 1;
+        }
+        self.dependencies.entry(before).or_default().insert(after);
+        Ok(newly_invalid)
+    }
+
+    // Removes `before|after`; returns the tracked updates that became valid because of it
+    pub fn remove_rule(&mut self, before: &P, after: &P) -> Vec<usize> {
+        if !self.dependencies.get_mut(before).is_some_and(|afters| afters.remove(after)) {
+            return Vec::new();
+        }
+
+        let mut newly_valid = Vec::new();
+        for id in self.updates_breaking(before, after) {
+            self.violation_counts[id] -= 1;
+            if self.violation_counts[id] == 0 {
+                newly_valid.push(id);
+            }
+        }
+        newly_valid
+    }
+}
+
+// A change to the rules, given on the command line with `--add-rule` or `--remove-rule`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleEdit<P> {
+    Add(P, P),
+    Remove(P, P),
+}
+
+// Applies the edits in order while keeping every update validated, reporting the updates
+// whose validity changes. Returns how many updates are valid at the end.
+pub fn apply_rule_edits<P: Page>(pairs: &[(P, P)], number_lists: &[Vec<P>], edits: &[RuleEdit<P>]) -> usize {
+    let mut rule_set = RuleSet::new(pairs);
+    let ids: Vec<usize> = number_lists.iter().map(|list| rule_set.track(list.clone())).collect();
+
+    for edit in edits {
+        match edit {
+            RuleEdit::Add(before, after) => match rule_set.add_rule(before.clone(), after.clone()) {
+                Ok(newly_invalid) => {
+                    info!("Added rule {}|{}: {} updates became invalid", before, after, newly_invalid.len());
+                    for id in newly_invalid {
+                        for violation in rule_set.violations(id) {
+                            verbose!("  Update {}: {}", id + 1, violation);
+                        }
+                    }
+                }
+                Err(error) => info!("Rejected {}", error),
+            },
+            RuleEdit::Remove(before, after) => {
+                let newly_valid = rule_set.remove_rule(before, after);
+                info!("Removed rule {}|{}: {} updates became valid", before, after, newly_valid.len());
+                for id in newly_valid {
+                    verbose!("  Update {} is now valid", id + 1);
+                }
+            }
+        }
+    }
+
+    let valid = ids.iter().filter(|&&id| rule_set.is_valid(id)).count();
+    println!("Valid updates after rule edits: {}", valid);
+    valid
+}
+
+// Adds the rules one at a time and reports the first one that closes a cycle
+pub fn first_cycle_rule<P: Page>(pairs: &[(P, P)]) -> Option<CycleError<P>> {
+    let mut rule_set = RuleSet::new(&[]);
+    pairs
+        .iter()
+        .find_map(|(before, after)| rule_set.add_rule(before.clone(), after.clone()).err())
+}
+
+// Rules compiled once into a bit matrix over densely numbered pages, so "must a come before b"
+// is a constant-time lookup instead of a hash set probe
+#[derive(Debug, Clone)]
+pub struct CompiledRules<P> {
+    page_index: HashMap<P, usize>,
+    words_per_row: usize,
+    bits: Vec<u64>,
+}
+
+impl<P: Page> CompiledRules<P> {
+    pub fn new(pairs: &[(P, P)]) -> CompiledRules<P> {
+        let mut page_index = HashMap::new();
+        for (before, after) in pairs {
+            let next = page_index.len();
+            page_index.entry(before.clone()).or_insert(next);
+            let next = page_index.len();
+            page_index.entry(after.clone()).or_insert(next);
+        }
+
+        let words_per_row = page_index.len().div_ceil(64);
+        let mut bits = vec![0u64; page_index.len() * words_per_row];
+        for (before, after) in pairs {
+            let row = page_index[before];
+            let column = page_index[after];
+            bits[row * words_per_row + column / 64] |= 1 << (column % 64);
+        }
+
+        CompiledRules {
+            page_index,
+            words_per_row,
+            bits,
+        }
+    }
+
+    // True if there is a rule `before|after`
+    pub fn must_precede(&self, before: &P, after: &P) -> bool {
+        match (self.page_index.get(before), self.page_index.get(after)) {
+            (Some(&row), Some(&column)) => {
+                self.bits[row * self.words_per_row + column / 64] & (1 << (column % 64)) != 0
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_valid(&self, list: &[P]) -> bool {
+        list.iter().enumerate().all(|(i, earlier)| {
+            list[i + 1..].iter().all(|later| !self.must_precede(later, earlier))
+        })
+    }
+
+    pub fn violations(&self, list: &[P]) -> Vec<RuleViolation<P>> {
+        list_violations(list, |before, after| self.must_precede(before, after))
+    }
+
+    pub fn compare(&self, a: &P, b: &P) -> Ordering {
+        if self.must_precede(a, b) {
+            Ordering::Less
+        } else if self.must_precede(b, a) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+
+    // The comparator is only a total order when the rules relate every pair of pages in the list
+    // and contain no cycle among them (as in the puzzle input). For such a "tournament" that holds
+    // exactly when every page has a different number of pages it must precede.
+    fn is_total_order(&self, list: &[P]) -> bool {
+        let mut precedes_count = vec![0; list.len()];
+        for (i, a) in list.iter().enumerate() {
+            for (j, b) in list.iter().enumerate().skip(i + 1) {
+                match self.compare(a, b) {
+                    Ordering::Less => precedes_count[i] += 1,
+                    Ordering::Greater => precedes_count[j] += 1,
+                    Ordering::Equal => return false,
+                }
+            }
+        }
+        let distinct: HashSet<usize> = precedes_count.iter().copied().collect();
+        distinct.len() == list.len()
+    }
+
+    // Comparator sort for lists fully ordered by the rules; None means the caller needs
+    // the general topological sort instead
+    pub fn sort(&self, list: &[P]) -> Option<Vec<P>> {
+        if !self.is_total_order(list) {
+            return None;
         }
+        let mut sorted = list.to_vec();
+        sorted.sort_by(|a, b| self.compare(a, b));
+        Some(sorted)
     }
-    true
 }
 
-fn build_graph(pairs: &[(u32, u32)], numbers: &[u32]) -> (HashMap<u32, Vec<u32>>, HashMap<u32, usize>) {
+type Graph<P> = HashMap<P, Vec<P>>;
+
+fn build_graph<P: Page>(pairs: &[(P, P)], numbers: &[P]) -> (Graph<P>, HashMap<P, usize>) {
     // Two things we're creating:
     // 1. graph: shows what each number must come before
     // 2. in_degree: counts how many numbers must come before each number
-    let mut graph: HashMap<u32, Vec<u32>> = HashMap::new();
-    let mut in_degree: HashMap<u32, usize> = HashMap::new();
+    let mut graph: Graph<P> = HashMap::new();
+    let mut in_degree: HashMap<P, usize> = HashMap::new();
 
     // First, make sure every number is in our maps, even if it has no rules
-    for &num in numbers {
-        graph.entry(num).or_default();  // Add empty Vec if number isn't there
-        in_degree.insert(num, 0);       // Start with 0 numbers that must come before it
+    for num in numbers {
+        graph.entry(num.clone()).or_default();  // Add empty Vec if number isn't there
+        in_degree.insert(num.clone(), 0);       // Start with 0 numbers that must come before it
     }
 
     // Now, for each rule like (47, 53) meaning "47 must come before 53":
-    for &(before, after) in pairs {
-        if numbers.contains(&before) && numbers.contains(&after) {
+    for (before, after) in pairs {
+        if numbers.contains(before) && numbers.contains(after) {
             // Add 'after' to the list of numbers that must come after 'before'
-            graph.entry(before).or_default().push(after);
+            graph.entry(before.clone()).or_default().push(after.clone());
             // Increment count of numbers that must come before 'after'
-            *in_degree.entry(after).or_default() += 1;
+            *in_degree.entry(after.clone()).or_default() += 1;
         }
     }
 
     (graph, in_degree)
 }
 
-fn topological_sort(pairs: &[(u32, u32)], numbers: &[u32]) -> Option<Vec<u32>> {
+fn topological_sort<P: Page>(pairs: &[(P, P)], numbers: &[P]) -> Option<Vec<P>> {
     let (graph, mut in_degree) = build_graph(pairs, numbers);
     let mut result = Vec::new();
     let mut queue = VecDeque::new();
 
     // Find all numbers that don't need anything before them
-    for &num in numbers {
-        if in_degree[&num] == 0 {
-            queue.push_back(num);
+    for num in numbers {
+        if in_degree[num] == 0 {
+            queue.push_back(num.clone());
         }
     }
 
     // While we have numbers we can place:
     while let Some(current) = queue.pop_front() {
         // Add this number to our result
-        result.push(current);
+        result.push(current.clone());
 
         // Look at all numbers that needed this number before them
         if let Some(neighbors) = graph.get(&current) {
-            for &next in neighbors {
+            for next in neighbors {
                 // This number is placed, so decrease the count for numbers waiting on it
-                *in_degree.get_mut(&next).unwrap() -= 1;
+                *in_degree.get_mut(next).unwrap() -= 1;
                 // If a number no longer needs any numbers before it, we can place it
-                if in_degree[&next] == 0 {
-                    queue.push_back(next);
+                if in_degree[next] == 0 {
+                    queue.push_back(next.clone());
                 }
             }
         }
@@ -178,53 +709,1000 @@ fn topological_sort(pairs: &[(u32, u32)], numbers: &[u32]) -> Option<Vec<u32>> {
     }
 }
 
-fn get_middle_number(list: &[u32]) -> u32 {
+// Which page to place next when several are ready
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderChoice {
+    // First ready page in input order (what topological_sort does)
+    InputOrder,
+    // Lexicographically smallest order
+    Smallest,
+    // Lexicographically largest order
+    Largest,
+}
+
+pub fn topological_sort_with<P: Page>(pairs: &[(P, P)], numbers: &[P], choice: OrderChoice) -> Option<Vec<P>> {
+    if choice == OrderChoice::InputOrder {
+        return topological_sort(pairs, numbers);
+    }
+
+    let (graph, mut in_degree) = build_graph(pairs, numbers);
+    let mut result = Vec::new();
+    let mut ready: BTreeSet<P> = numbers.iter().filter(|&num| in_degree[num] == 0).cloned().collect();
+
+    loop {
+        let next = match choice {
+            OrderChoice::Largest => ready.pop_last(),
+            _ => ready.pop_first(),
+        };
+        let Some(current) = next else { break };
+
+        for next in &graph[&current] {
+            let degree = in_degree.get_mut(next).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.insert(next.clone());
+            }
+        }
+        result.push(current);
+    }
+
+    if result.len() == numbers.len() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+// The order is unique exactly when Kahn's algorithm never has more than one page to choose from
+pub fn has_unique_order<P: Page>(pairs: &[(P, P)], numbers: &[P]) -> bool {
+    let (graph, mut in_degree) = build_graph(pairs, numbers);
+    let mut ready: Vec<P> = numbers.iter().filter(|&num| in_degree[num] == 0).cloned().collect();
+    let mut placed = 0;
+
+    while ready.len() == 1 {
+        let current = ready.pop().unwrap();
+        placed += 1;
+        for next in &graph[&current] {
+            let degree = in_degree.get_mut(next).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.push(next.clone());
+            }
+        }
+    }
+
+    ready.is_empty() && placed == numbers.len()
+}
+
+// Every page that is the middle page of at least one valid order. A page can be placed at
+// position k exactly when it has at most k pages that must precede it and at most n - 1 - k
+// that must follow it. Returns None if the rules for this list contain a cycle.
+pub fn possible_middle_pages<P: Page>(pairs: &[(P, P)], numbers: &[P]) -> Option<BTreeSet<P>> {
+    topological_sort(pairs, numbers)?;
+
+    let closure = update_closure(pairs, numbers);
+    let middle = numbers.len() / 2;
+    let mut ancestors: HashMap<&P, usize> = numbers.iter().map(|num| (num, 0)).collect();
+    for followers in closure.values() {
+        for follower in followers {
+            *ancestors.get_mut(follower).unwrap() += 1;
+        }
+    }
+
+    Some(numbers
+        .iter()
+        .filter(|&num| ancestors[num] <= middle && closure[num].len() < numbers.len() - middle)
+        .cloned()
+        .collect())
+}
+
+// Lists valid orders in lexicographic order, stopping after `limit` of them
+pub fn all_topological_orders<P: Page>(pairs: &[(P, P)], numbers: &[P], limit: usize) -> Vec<Vec<P>> {
+    fn extend<P: Page>(
+        graph: &Graph<P>,
+        in_degree: &mut HashMap<P, usize>,
+        ready: &mut BTreeSet<P>,
+        order: &mut Vec<P>,
+        total: usize,
+        orders: &mut Vec<Vec<P>>,
+        limit: usize,
+    ) {
+        if orders.len() >= limit {
+            return;
+        }
+        if order.len() == total {
+            orders.push(order.clone());
+            return;
+        }
+
+        let candidates: Vec<P> = ready.iter().cloned().collect();
+        for current in candidates {
+            ready.remove(&current);
+            for next in &graph[&current] {
+                let degree = in_degree.get_mut(next).unwrap();
+                *degree -= 1;
+                if *degree == 0 {
+                    ready.insert(next.clone());
+                }
+            }
+            order.push(current.clone());
+
+            extend(graph, in_degree, ready, order, total, orders, limit);
+
+            order.pop();
+            for next in &graph[&current] {
+                let degree = in_degree.get_mut(next).unwrap();
+                if *degree == 0 {
+                    ready.remove(next);
+                }
+                *degree += 1;
+            }
+            ready.insert(current);
+        }
+    }
+
+    let (graph, mut in_degree) = build_graph(pairs, numbers);
+    let mut ready: BTreeSet<P> = numbers.iter().filter(|&num| in_degree[num] == 0).cloned().collect();
+    let mut orders = Vec::new();
+    extend(&graph, &mut in_degree, &mut ready, &mut Vec::new(), numbers.len(), &mut orders, limit);
+    orders
+}
+
+// One step of a minimal repair, with indices into the original list (`to` is the index in the repaired list)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit<P> {
+    Move { page: P, from: usize, to: usize },
+    Remove { page: P, index: usize },
+}
+
+impl<P: fmt::Display> fmt::Display for Edit<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edit::Move { page, from, to } => write!(f, "move {} from index {} to index {}", page, from, to),
+            Edit::Remove { page, index } => write!(f, "remove {} at index {}", page, index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair<P> {
+    pub result: Vec<P>,
+    pub edits: Vec<Edit<P>>,
+}
+
+// Largest set of indices (kept in order) with no conflicting pair, where `conflict(i, j)` with i < j
+// means the two can't both stay. This is a branch and bound search, which is fine for update-sized lists.
+fn largest_compatible_subsequence(len: usize, conflict: impl Fn(usize, usize) -> bool) -> Vec<usize> {
+    fn search(
+        position: usize,
+        len: usize,
+        conflict: &dyn Fn(usize, usize) -> bool,
+        kept: &mut Vec<usize>,
+        best: &mut Vec<usize>,
+    ) {
+        if kept.len() + (len - position) <= best.len() {
+            return;
+        }
+        if position == len {
+            *best = kept.clone();
+            return;
+        }
+
+        if kept.iter().all(|&earlier| !conflict(earlier, position)) {
+            kept.push(position);
+            search(position + 1, len, conflict, kept, best);
+            kept.pop();
+        }
+        search(position + 1, len, conflict, kept, best);
+    }
+
+    let mut best = Vec::new();
+    search(0, len, &conflict, &mut Vec::new(), &mut best);
+    best
+}
+
+// Fewest pages to move so the list satisfies the rules. The pages left in place must not have a
+// later page that (directly or through other pages) has to come before an earlier one.
+// Returns None if the rules for this list contain a cycle.
+pub fn repair_by_moves<P: Page>(pairs: &[(P, P)], list: &[P]) -> Option<Repair<P>> {
+    topological_sort(pairs, list)?;
+    let closure = update_closure(pairs, list);
+    let kept = largest_compatible_subsequence(list.len(), |i, j| closure[&list[j]].contains(&list[i]));
+
+    // Pin the kept pages in their current relative order and let the sort place the rest
+    let mut constraints = pairs.to_vec();
+    constraints.extend(kept.windows(2).map(|window| (list[window[0]].clone(), list[window[1]].clone())));
+    let result = topological_sort(&constraints, list)?;
+
+    let kept_pages: HashSet<&P> = kept.iter().map(|&index| &list[index]).collect();
+    let edits = result
+        .iter()
+        .enumerate()
+        .filter(|(_, page)| !kept_pages.contains(page))
+        .map(|(to, page)| Edit::Move {
+            page: page.clone(),
+            from: list.iter().position(|p| p == page).unwrap(),
+            to,
+        })
+        .collect();
+
+    Some(Repair { result, edits })
+}
+
+// Fewest pages to drop so the remaining pages break no rule
+pub fn repair_by_removals<P: Page>(rules: &CompiledRules<P>, list: &[P]) -> Repair<P> {
+    let kept = largest_compatible_subsequence(list.len(), |i, j| rules.must_precede(&list[j], &list[i]));
+    let kept_indices: HashSet<usize> = kept.iter().copied().collect();
+
+    let result = kept.iter().map(|&index| list[index].clone()).collect();
+    let edits = list
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !kept_indices.contains(index))
+        .map(|(index, page)| Edit::Remove { page: page.clone(), index })
+        .collect();
+
+    Repair { result, edits }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+// Renders the rule graph as Graphviz DOT or Mermaid. With an update given, only its pages are
+// drawn and the rules it breaks are highlighted in red.
+pub fn render_graph<P: Page>(pairs: &[(P, P)], update: Option<&[P]>, format: GraphFormat) -> String {
+    let pages: Vec<P> = match update {
+        Some(list) => list.to_vec(),
+        None => {
+            let all: BTreeSet<P> = pairs.iter().flat_map(|(before, after)| [before.clone(), after.clone()]).collect();
+            all.into_iter().collect()
+        }
+    };
+    let (graph, _) = build_graph(pairs, &pages);
+
+    let position: HashMap<&P, usize> = pages.iter().enumerate().map(|(i, page)| (page, i)).collect();
+    let is_violated = |before: &P, after: &P| update.is_some() && position[after] < position[before];
+
+    let mut nodes: Vec<&P> = graph.keys().collect();
+    nodes.sort_unstable();
+    let node_id: HashMap<&P, usize> = nodes.iter().enumerate().map(|(i, &page)| (page, i)).collect();
+    let mut edges: Vec<(&P, &P)> = graph
+        .iter()
+        .flat_map(|(before, afters)| afters.iter().map(move |after| (before, after)))
+        .collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    // Page names can be arbitrary text, so they are always quoted
+    let dot_label = |page: &P| format!("\"{}\"", page.to_string().replace('\\', "\\\\").replace('"', "\\\""));
+    let mermaid_label = |page: &P| format!("\"{}\"", page.to_string().replace('"', "#quot;"));
+
+    let mut out = String::new();
+    match format {
+        GraphFormat::Dot => {
+            out.push_str("digraph rules {\n");
+            for node in &nodes {
+                out.push_str(&format!("    {};\n", dot_label(node)));
+            }
+            for &(before, after) in &edges {
+                if is_violated(before, after) {
+                    out.push_str(&format!("    {} -> {} [color=red, penwidth=2];\n", dot_label(before), dot_label(after)));
+                } else {
+                    out.push_str(&format!("    {} -> {};\n", dot_label(before), dot_label(after)));
+                }
+            }
+            out.push_str("}\n");
+        }
+        GraphFormat::Mermaid => {
+            out.push_str("graph LR\n");
+            for node in &nodes {
+                out.push_str(&format!("    p{}[{}]\n", node_id[node], mermaid_label(node)));
+            }
+            // Mermaid styles edges by their index in declaration order
+            let mut violated_links = Vec::new();
+            for (i, &(before, after)) in edges.iter().enumerate() {
+                out.push_str(&format!("    p{} --> p{}\n", node_id[before], node_id[after]));
+                if is_violated(before, after) {
+                    violated_links.push(i.to_string());
+                }
+            }
+            if !violated_links.is_empty() {
+                out.push_str(&format!("    linkStyle {} stroke:red,stroke-width:2px\n", violated_links.join(",")));
+            }
+        }
+    }
+    out
+}
+
+// Writes the rule graph, optionally for one update (1-based), to `output_path`
+pub fn export_graph<P: Page>(
+    pairs: &[(P, P)],
+    number_lists: &[Vec<P>],
+    format: GraphFormat,
+    update_number: Option<usize>,
+    output_path: &str,
+) -> Result<(), ParseError> {
+    let update = match update_number {
+        Some(number) => Some(
+            number
+                .checked_sub(1)
+                .and_then(|index| number_lists.get(index))
+                .ok_or_else(|| ParseError::new(&format!("No update number {} in input", number)))?
+                .as_slice(),
+        ),
+        None => None,
+    };
+
+    fs::write(output_path, render_graph(pairs, update, format))?;
+    Ok(())
+}
+
+// Problems found while auditing a rule set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleIssue<P> {
+    // The same rule appears more than once
+    Duplicate((P, P)),
+    // Both a|b and b|a are present
+    Contradiction((P, P)),
+    // a|b already follows from other rules, e.g. a|c and c|b
+    Redundant { rule: (P, P), via: Vec<P> },
+}
+
+impl<P: fmt::Display> fmt::Display for RuleIssue<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleIssue::Duplicate((a, b)) => write!(f, "duplicate rule {}|{}", a, b),
+            RuleIssue::Contradiction((a, b)) => write!(f, "rule {}|{} contradicts {}|{}", a, b, b, a),
+            RuleIssue::Redundant { rule: (a, b), via } => {
+                let path: Vec<String> = via.iter().map(|page| page.to_string()).collect();
+                write!(f, "rule {}|{} is implied by {}", a, b, path.join(" -> "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RulesReport<P> {
+    // Groups of pages that are all mutually ordered before each other (strongly connected components)
+    pub components: Vec<Vec<P>>,
+    // Individual cycles, each listed once starting from its smallest page
+    pub cycles: Vec<Vec<P>>,
+    // True if cycle enumeration stopped at the limit
+    pub cycles_truncated: bool,
+    pub issues: Vec<RuleIssue<P>>,
+}
+
+fn adjacency<P: Page>(pairs: &[(P, P)]) -> Graph<P> {
+    let mut graph: Graph<P> = HashMap::new();
+    for (before, after) in pairs {
+        let targets = graph.entry(before.clone()).or_default();
+        if !targets.contains(after) {
+            targets.push(after.clone());
+        }
+        graph.entry(after.clone()).or_default();
+    }
+    for targets in graph.values_mut() {
+        targets.sort_unstable();
+    }
+    graph
+}
+
+// Tarjan's algorithm; only components with an actual cycle are returned
+fn strongly_connected_components<P: Page>(graph: &Graph<P>) -> Vec<Vec<P>> {
+    struct State<P> {
+        counter: usize,
+        index: HashMap<P, usize>,
+        low_link: HashMap<P, usize>,
+        stack: Vec<P>,
+        on_stack: HashSet<P>,
+        components: Vec<Vec<P>>,
+    }
+
+    fn visit<P: Page>(node: &P, graph: &Graph<P>, state: &mut State<P>) {
+        state.index.insert(node.clone(), state.counter);
+        state.low_link.insert(node.clone(), state.counter);
+        state.counter += 1;
+        state.stack.push(node.clone());
+        state.on_stack.insert(node.clone());
+
+        for next in &graph[node] {
+            if !state.index.contains_key(next) {
+                visit(next, graph, state);
+                let low = state.low_link[node].min(state.low_link[next]);
+                state.low_link.insert(node.clone(), low);
+            } else if state.on_stack.contains(next) {
+                let low = state.low_link[node].min(state.index[next]);
+                state.low_link.insert(node.clone(), low);
+            }
+        }
+
+        if state.low_link[node] == state.index[node] {
+            let mut component = Vec::new();
+            while let Some(member) = state.stack.pop() {
+                state.on_stack.remove(&member);
+                let done = member == *node;
+                component.push(member);
+                if done {
+                    break;
+                }
+            }
+            component.sort_unstable();
+            state.components.push(component);
+        }
+    }
+
+    let mut state = State {
+        counter: 0,
+        index: HashMap::new(),
+        low_link: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        components: Vec::new(),
+    };
+
+    let mut nodes: Vec<&P> = graph.keys().collect();
+    nodes.sort_unstable();
+    for node in nodes {
+        if !state.index.contains_key(node) {
+            visit(node, graph, &mut state);
+        }
+    }
+
+    let mut components: Vec<Vec<P>> = state.components
+        .into_iter()
+        .filter(|component| component.len() > 1 || graph[&component[0]].contains(&component[0]))
+        .collect();
+    components.sort();
+    components
+}
+
+// Lists elementary cycles, each starting from its smallest page, stopping after `limit` cycles.
+// The full AoC rule set has an enormous number of cycles, so the limit is required.
+fn elementary_cycles<P: Page>(graph: &Graph<P>, limit: usize) -> (Vec<Vec<P>>, bool) {
+    fn search<P: Page>(
+        start: &P,
+        node: &P,
+        graph: &Graph<P>,
+        path: &mut Vec<P>,
+        on_path: &mut HashSet<P>,
+        cycles: &mut Vec<Vec<P>>,
+        limit: usize,
+    ) -> bool {
+        for next in &graph[node] {
+            if cycles.len() >= limit {
+                return false;
+            }
+            if next == start {
+                cycles.push(path.clone());
+            } else if next > start && !on_path.contains(next) {
+                path.push(next.clone());
+                on_path.insert(next.clone());
+                let complete = search(start, next, graph, path, on_path, cycles, limit);
+                on_path.remove(next);
+                path.pop();
+                if !complete {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    let mut cycles = Vec::new();
+    for component in strongly_connected_components(graph) {
+        for start in &component {
+            let mut path = vec![start.clone()];
+            let mut on_path = HashSet::from([start.clone()]);
+            if !search(start, start, graph, &mut path, &mut on_path, &mut cycles, limit) {
+                return (cycles, true);
+            }
+        }
+    }
+    (cycles, false)
+}
+
+// Shortest path from `from` to `to` that does not use the direct edge between them
+fn path_avoiding_direct_edge<'a, P: Page>(graph: &'a Graph<P>, from: &'a P, to: &'a P) -> Option<Vec<P>> {
+    let mut previous: HashMap<&P, &P> = HashMap::new();
+    let mut queue = VecDeque::new();
+
+    for next in &graph[from] {
+        if next != to && next != from && !previous.contains_key(next) {
+            previous.insert(next, from);
+            queue.push_back(next);
+        }
+    }
+
+    while let Some(current) = queue.pop_front() {
+        for next in &graph[current] {
+            if next == from || previous.contains_key(next) {
+                continue;
+            }
+            previous.insert(next, current);
+            if next == to {
+                let mut path = vec![to.clone()];
+                let mut step = to;
+                while step != from {
+                    step = previous[step];
+                    path.push(step.clone());
+                }
+                path.reverse();
+                return Some(path);
+            }
+            queue.push_back(next);
+        }
+    }
+    None
+}
+
+pub fn analyze_rules<P: Page>(pairs: &[(P, P)], cycle_limit: usize) -> RulesReport<P> {
+    let graph = adjacency(pairs);
+    let components = strongly_connected_components(&graph);
+    let (cycles, cycles_truncated) = elementary_cycles(&graph, cycle_limit);
+
+    let mut issues = Vec::new();
+    let mut seen = HashSet::new();
+    let unique_rules: HashSet<(&P, &P)> = pairs.iter().map(|(before, after)| (before, after)).collect();
+
+    for (before, after) in pairs {
+        if !seen.insert((before, after)) {
+            issues.push(RuleIssue::Duplicate((before.clone(), after.clone())));
+            continue;
+        }
+        // Report each contradicting pair once
+        if before < after && unique_rules.contains(&(after, before)) {
+            issues.push(RuleIssue::Contradiction((before.clone(), after.clone())));
+        }
+        if let Some(via) = path_avoiding_direct_edge(&graph, before, after) {
+            issues.push(RuleIssue::Redundant { rule: (before.clone(), after.clone()), via });
+        }
+    }
+
+    RulesReport {
+        components,
+        cycles,
+        cycles_truncated,
+        issues,
+    }
+}
+
+// Transitive closure of the rules, restricted to the pages of one update:
+// closure[page] holds every page of the update that must come after `page`
+pub fn update_closure<P: Page>(pairs: &[(P, P)], numbers: &[P]) -> HashMap<P, HashSet<P>> {
+    let (graph, _) = build_graph(pairs, numbers);
+    let mut closure = HashMap::new();
+
+    for start in numbers {
+        let mut reachable = HashSet::new();
+        let mut stack = graph[start].clone();
+        while let Some(current) = stack.pop() {
+            if !reachable.contains(&current) {
+                stack.extend(graph[&current].iter().cloned());
+                reachable.insert(current);
+            }
+        }
+        closure.insert(start.clone(), reachable);
+    }
+
+    closure
+}
+
+fn get_middle_number<P: Clone>(list: &[P]) -> P {
     let middle_index = list.len() / 2;
-    list[middle_index]
+    list[middle_index].clone()
 }
 
-pub fn process(input_file_name: &str) -> Result<(), ParseError> {
-    let (pairs, number_lists) = parse_file(input_file_name)?;
-    let results = validate_lists(&pairs, &number_lists);
+// Totals for both parts of the puzzle, plus how each update was handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateSummary {
+    // Part 1: middle pages of updates that were already in the right order
+    pub valid_middle_sum: u32,
+    // Part 2: middle pages of updates after reordering
+    pub reordered_middle_sum: u32,
+    pub valid_count: usize,
+    pub fixed_count: usize,
+    pub unfixable_count: usize,
+}
 
-    let mut sum_of_reordered_middles = 0;
+// Checks and reorders every update. `choice` picks the reordering when the rules allow
+// several; InputOrder keeps the fast comparator sort used for the puzzle
+pub fn summarize(pairs: &[(u32, u32)], number_lists: &[Vec<u32>], choice: OrderChoice) -> UpdateSummary {
+    let rules = CompiledRules::new(pairs);
+    let results = validate_lists(&rules, number_lists);
+    trace!("Compiled {} rules over {} pages", pairs.len(), rules.page_index.len());
 
-    println!("Processing lists:");
-    for (i, (list, is_valid)) in number_lists.iter().zip(results.iter()).enumerate() {
-        println!("\nList {}: {:?}", i + 1, list);
-        println!("Initially valid: {}", is_valid);
+    // The audit walks every rule, so it only runs when asked for
+    if output::enabled(Verbosity::Verbose) {
+        let report = analyze_rules(pairs, 10);
+        // Redundant rules are harmless, so they are listed but not counted as problems
+        let problems = report.issues.iter()
+            .filter(|issue| !matches!(issue, RuleIssue::Redundant { .. }))
+            .count();
+        verbose!("Rule audit: {} cyclic page groups, {} issues", report.components.len(), problems);
+        for cycle in &report.cycles {
+            verbose!("Cycle: {:?}", cycle);
+        }
+        if report.cycles_truncated {
+            verbose!("(cycle listing truncated)");
+        }
+        // Replaying the rules is slow, and only finds something when there is a cycle
+        if !report.components.is_empty() {
+            if let Some(error) = first_cycle_rule(pairs) {
+                verbose!("First rule to close a cycle: {}", error);
+            }
+        }
+        for issue in &report.issues {
+            trace!("Rule issue: {}", issue);
+        }
+    }
+
+    let mut summary = UpdateSummary::default();
+
+    verbose!("Processing lists:");
+    for (i, (list, violations)) in number_lists.iter().zip(results.iter()).enumerate() {
+        let is_valid = violations.is_empty();
+        verbose!("\nList {}: {:?}", i + 1, list);
+        verbose!("Initially valid: {}", is_valid);
+        for violation in violations {
+            verbose!("  {}", violation);
+        }
+
+        if is_valid {
+            summary.valid_count += 1;
+            summary.valid_middle_sum += get_middle_number(list);
+        } else {
+            if output::enabled(Verbosity::Trace) {
+                let (graph, in_degree) = build_graph(pairs, list);
+                let mut pages: Vec<&u32> = graph.keys().collect();
+                pages.sort_unstable();
+                for page in pages {
+                    trace!("  {} (in-degree {}) -> {:?}", page, in_degree[page], graph[page]);
+                }
+            }
+            if output::enabled(Verbosity::Verbose) {
+                if let Some(moves) = repair_by_moves(pairs, list) {
+                    let removals = repair_by_removals(&rules, list);
+                    verbose!("Minimal repair: {} moves or {} removals", moves.edits.len(), removals.edits.len());
+                }
+            }
 
-        if !is_valid {
-            if let Some(reordered) = topological_sort(&pairs, list) {
-                println!("Reordered: {:?}", reordered);
+            // Fall back to Kahn's algorithm when the rules don't fully order this list
+            let reordered = match choice {
+                OrderChoice::InputOrder => rules.sort(list).or_else(|| topological_sort(pairs, list)),
+                _ => topological_sort_with(pairs, list, choice),
+            };
+            if let Some(reordered) = reordered {
+                verbose!("Reordered: {:?}", reordered);
 
                 // Validate the reordered list
-                if validate_single_list(&reordered, &dependencies_map(&pairs)) {
+                if rules.is_valid(&reordered) {
                     let middle = get_middle_number(&reordered);
-                    println!("Middle number after reordering: {}", middle);
-                    sum_of_reordered_middles += middle;
+                    verbose!("Middle number after reordering: {}", middle);
+                    if output::enabled(Verbosity::Normal) {
+                        if let Some(middles) = possible_middle_pages(pairs, list) {
+                            if middles.len() > 1 {
+                                info!("Warning: order of list {} is not unique, possible middle pages: {:?}",
+                                      i + 1, middles);
+                            }
+                        }
+                    }
+                    // Enumerating orders is exponential in the worst case, so only do it when tracing
+                    if output::enabled(Verbosity::Trace) && !has_unique_order(pairs, list) {
+                        for order in all_topological_orders(pairs, list, 5) {
+                            trace!("  Possible order: {:?}", order);
+                        }
+                    }
+                    summary.fixed_count += 1;
+                    summary.reordered_middle_sum += middle;
                 } else {
-                    println!("Warning: Reordered list is still invalid!");
+                    info!("Warning: Reordered list {} is still invalid!", i + 1);
+                    summary.unfixable_count += 1;
                 }
             } else {
-                println!("Warning: Could not reorder list (circular dependencies detected)");
+                info!("Warning: Could not reorder list {} (circular dependencies detected)", i + 1);
+                summary.unfixable_count += 1;
             }
         }
     }
 
-    println!("\nSum of middle numbers from successfully reordered lists: {}",
-             sum_of_reordered_middles);
+    info!("\nValid: {}, fixed: {}, unfixable: {}",
+          summary.valid_count, summary.fixed_count, summary.unfixable_count);
+    summary
+}
 
-    Ok(())
+pub fn process(pairs: &[(u32, u32)], number_lists: &[Vec<u32>], choice: OrderChoice) -> UpdateSummary {
+    let summary = summarize(pairs, number_lists, choice);
+
+    println!("Sum of middle numbers from initially valid lists: {}", summary.valid_middle_sum);
+    println!("Sum of middle numbers from successfully reordered lists: {}",
+             summary.reordered_middle_sum);
+
+    summary
 }
 
-fn dependencies_map(pairs: &[(u32, u32)]) -> HashMap<u32, HashSet<u32>> {
+fn dependencies_map<P: Page>(pairs: &[(P, P)]) -> HashMap<P, HashSet<P>> {
     let mut dependencies = HashMap::new();
-    for &(before, after) in pairs {
+    for (before, after) in pairs {
         dependencies
-            .entry(before)
+            .entry(before.clone())
             .or_insert_with(HashSet::new)
-            .insert(after);
+            .insert(after.clone());
     }
     dependencies
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_text_u32(contents: &str) -> Result<(Rules<u32>, Updates<u32>), ParseError> {
+        parse_str(contents, InputFormat::Text)
+    }
+
+    #[test]
+    fn comment_lines_do_not_end_the_rules_section() {
+        let (pairs, updates) = parse_text_u32("# rules\n47|53\n# more\n97|13\n\n47,53").unwrap();
+        assert_eq!(pairs, vec![(47, 53), (97, 13)]);
+        assert_eq!(updates, vec![vec![47, 53]]);
+    }
+
+    #[test]
+    fn trailing_comments_and_commas_are_ignored() {
+        let (pairs, updates) = parse_text_u32("47|53 # why\n\n47,53, # first\n# skipped\n1,2,3,\n").unwrap();
+        assert_eq!(pairs, vec![(47, 53)]);
+        assert_eq!(updates, vec![vec![47, 53], vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn whitespace_only_line_separates_sections() {
+        let (pairs, updates) = parse_text_u32("1|2\n  \t\n2,1\n").unwrap();
+        assert_eq!(pairs, vec![(1, 2)]);
+        assert_eq!(updates, vec![vec![2, 1]]);
+    }
+
+    #[test]
+    fn hash_inside_page_names_is_not_a_comment() {
+        let (pairs, updates) = parse_str::<String>("# languages\nC#|F# # dotnet\nF#|#12\n\nC#,#12,F#, # all\n", InputFormat::Text).unwrap();
+        assert_eq!(pairs, vec![("C#".to_string(), "F#".to_string()), ("F#".to_string(), "#12".to_string())]);
+        assert_eq!(updates, vec![vec!["C#".to_string(), "#12".to_string(), "F#".to_string()]]);
+    }
+
+    #[test]
+    fn empty_updates_are_rejected() {
+        let error = parse_str::<u32>("rule,1,2\nupdate,1,2\nupdate,\n", InputFormat::Csv).unwrap_err();
+        assert_eq!(error.location, Some((Section::Updates, 3, 7)));
+
+        let error = parse_str::<u32>(r#"{"rules": [[1, 2]], "updates": [[1, 2], []]}"#, InputFormat::Json).unwrap_err();
+        assert!(error.message.contains("updates[1]"), "{}", error);
+
+        let error = parse_text_u32("1|2\n\n1,2\n,\n").unwrap_err();
+        assert_eq!(error.location.map(|(section, line, _)| (section, line)), Some((Section::Updates, 4)));
+    }
+
+    #[test]
+    fn missing_separator_is_an_error() {
+        let error = parse_text_u32("1|2\n# no blank line\n2,1\n").unwrap_err();
+        assert_eq!(error.location, Some((Section::Rules, 3, 1)));
+    }
+
+    #[test]
+    fn violation_names_both_pages_and_the_rule() {
+        let rules = CompiledRules::new(&[(97, 13)]);
+        let violations = rules.violations(&[61, 13, 29, 97]);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].to_string(), "page 13 at index 1 must come after 97 at index 3 (rule 97|13)");
+    }
+
+    // Every permutation of the list that breaks no rule, in lexicographic order
+    fn valid_permutations(pairs: &[(u32, u32)], numbers: &[u32]) -> Vec<Vec<u32>> {
+        fn permute(rest: &mut Vec<u32>, current: &mut Vec<u32>, out: &mut Vec<Vec<u32>>) {
+            if rest.is_empty() {
+                out.push(current.clone());
+                return;
+            }
+            for i in 0..rest.len() {
+                let page = rest.remove(i);
+                current.push(page);
+                permute(rest, current, out);
+                current.pop();
+                rest.insert(i, page);
+            }
+        }
+
+        let rules = CompiledRules::new(pairs);
+        let mut sorted = numbers.to_vec();
+        sorted.sort_unstable();
+        let mut all = Vec::new();
+        permute(&mut sorted, &mut Vec::new(), &mut all);
+        all.into_iter().filter(|order| rules.is_valid(order)).collect()
+    }
+
+    fn small_cases() -> Vec<(Rules<u32>, Vec<u32>)> {
+        vec![
+            (vec![(1, 2), (1, 3)], vec![3, 2, 1]),
+            (vec![(1, 2), (2, 3), (3, 4)], vec![4, 3, 2, 1]),
+            (vec![(5, 1), (4, 2)], vec![1, 2, 4, 5]),
+            (vec![(2, 1), (3, 1), (4, 5)], vec![1, 2, 3, 4, 5]),
+            (vec![(1, 2), (2, 1)], vec![1, 2]),
+            (vec![], vec![3, 1, 2]),
+        ]
+    }
+
+    #[test]
+    fn rule_set_tracks_newly_invalid_and_valid_updates() {
+        let mut rule_set = RuleSet::new(&[(1, 2)]);
+        let in_order = rule_set.track(vec![1, 2, 3]);
+        let reversed = rule_set.track(vec![3, 2, 1]);
+        let unrelated = rule_set.track(vec![4, 5]);
+        assert!(rule_set.is_valid(in_order));
+        assert!(!rule_set.is_valid(reversed));
+        assert_eq!(rule_set.violations(reversed).len(), 1);
+
+        // Breaks only the first update; the reversed one was already invalid
+        assert_eq!(rule_set.add_rule(3, 2), Ok(vec![in_order]));
+        assert_eq!(rule_set.violations(reversed).len(), 1);
+        assert_eq!(rule_set.add_rule(3, 2), Ok(Vec::new()));
+        assert_eq!(rule_set.add_rule(5, 4), Ok(vec![unrelated]));
+
+        assert_eq!(rule_set.remove_rule(&1, &2), vec![reversed]);
+        assert!(!rule_set.is_valid(in_order));
+        assert_eq!(rule_set.remove_rule(&3, &2), vec![in_order]);
+        assert!(rule_set.remove_rule(&3, &2).is_empty());
+        assert_eq!(rule_set.remove_rule(&5, &4), vec![unrelated]);
+    }
+
+    #[test]
+    fn rule_set_rejects_rules_closing_a_cycle() {
+        let mut rule_set = RuleSet::new(&[(1, 2), (2, 3)]);
+        let error = rule_set.add_rule(3, 1).unwrap_err();
+        assert_eq!(error.rule, (3, 1));
+        assert_eq!(error.cycle, vec![3, 1, 2, 3]);
+        // The rejected rule was not added
+        assert!(!rule_set.contains(&3, &1));
+        assert_eq!(rule_set.add_rule(1, 3), Ok(Vec::new()));
+
+        let error = first_cycle_rule(&[(1, 2), (2, 3), (4, 5), (3, 1), (5, 4)]).unwrap();
+        assert_eq!(error.rule, (3, 1));
+        assert!(first_cycle_rule(&[(1, 2), (2, 3), (1, 3)]).is_none());
+    }
+
+    #[test]
+    fn smallest_and_largest_orders_match_brute_force() {
+        for (pairs, numbers) in small_cases() {
+            let valid = valid_permutations(&pairs, &numbers);
+            assert_eq!(topological_sort_with(&pairs, &numbers, OrderChoice::Smallest), valid.first().cloned());
+            assert_eq!(topological_sort_with(&pairs, &numbers, OrderChoice::Largest), valid.last().cloned());
+        }
+    }
+
+    #[test]
+    fn unique_order_and_listing_match_brute_force() {
+        for (pairs, numbers) in small_cases() {
+            let valid = valid_permutations(&pairs, &numbers);
+            assert_eq!(has_unique_order(&pairs, &numbers), valid.len() == 1);
+            assert_eq!(all_topological_orders(&pairs, &numbers, 1000), valid);
+            assert_eq!(all_topological_orders(&pairs, &numbers, 2), valid.into_iter().take(2).collect::<Vec<_>>());
+        }
+    }
+
+    // Small rule sets and updates from a fixed LCG; rules only point from smaller to larger pages
+    // unless `allow_cycles` is set
+    fn random_cases(count: usize, allow_cycles: bool) -> Vec<(Rules<u32>, Vec<u32>)> {
+        let mut state = 12345u64;
+        let mut next = |bound: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % bound
+        };
+
+        (0..count)
+            .map(|_| {
+                let len = 2 + next(5) as u32;
+                let mut pairs = Vec::new();
+                for _ in 0..next(8) {
+                    let a = 1 + next(len as u64) as u32;
+                    let b = 1 + next(len as u64) as u32;
+                    if a != b && !pairs.contains(&(a, b)) {
+                        pairs.push(if allow_cycles { (a, b) } else { (a.min(b), a.max(b)) });
+                    }
+                }
+                let mut list: Vec<u32> = (1..=len).collect();
+                for i in (1..list.len()).rev() {
+                    list.swap(i, next(i as u64 + 1) as usize);
+                }
+                (pairs, list)
+            })
+            .collect()
+    }
+
+    // Length of the longest common subsequence of two lists
+    fn common_subsequence_len(a: &[u32], b: &[u32]) -> usize {
+        let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                lengths[i + 1][j + 1] = if a[i] == b[j] {
+                    lengths[i][j] + 1
+                } else {
+                    lengths[i][j + 1].max(lengths[i + 1][j])
+                };
+            }
+        }
+        lengths[a.len()][b.len()]
+    }
+
+    #[test]
+    fn repair_by_moves_is_minimal() {
+        for (pairs, list) in small_cases().into_iter().chain(random_cases(200, false)) {
+            let valid = valid_permutations(&pairs, &list);
+            let Some(repair) = repair_by_moves(&pairs, &list) else {
+                assert!(valid.is_empty(), "{:?} {:?}", pairs, list);
+                continue;
+            };
+
+            // Every valid order can be reached by moving the pages outside a common subsequence
+            let kept = valid.iter().map(|order| common_subsequence_len(&list, order)).max().unwrap();
+            assert_eq!(repair.edits.len(), list.len() - kept, "{:?} {:?}", pairs, list);
+            assert!(valid.contains(&repair.result), "{:?} {:?}", pairs, list);
+
+            // The pages that were not moved keep their original relative order
+            let moved: Vec<&u32> = repair.edits.iter().map(|edit| match edit {
+                Edit::Move { page, .. } => page,
+                Edit::Remove { .. } => panic!("unexpected removal"),
+            }).collect();
+            let stayed = |pages: &[u32]| pages.iter().filter(|page| !moved.contains(page)).copied().collect::<Vec<_>>();
+            assert_eq!(stayed(&list), stayed(&repair.result));
+        }
+    }
+
+    #[test]
+    fn repair_by_removals_is_minimal() {
+        let cases = small_cases().into_iter().chain(random_cases(100, false)).chain(random_cases(100, true));
+        for (pairs, list) in cases {
+            let rules = CompiledRules::new(&pairs);
+            let largest_valid_subset = (0u32..1 << list.len())
+                .map(|mask| (0..list.len()).filter(|i| mask & (1 << i) != 0).map(|i| list[i]).collect::<Vec<_>>())
+                .filter(|subset| rules.is_valid(subset))
+                .map(|subset| subset.len())
+                .max()
+                .unwrap();
+
+            let repair = repair_by_removals(&rules, &list);
+            assert_eq!(repair.edits.len(), list.len() - largest_valid_subset, "{:?} {:?}", pairs, list);
+            assert_eq!(repair.result.len(), largest_valid_subset);
+            assert!(rules.is_valid(&repair.result), "{:?} {:?}", pairs, list);
+            assert_eq!(common_subsequence_len(&list, &repair.result), repair.result.len());
+        }
+    }
+
+    const SAMPLE: &str = "47|53\n97|13\n97|61\n97|47\n75|29\n61|13\n75|53\n29|13\n97|29\n53|29\n61|53\n\
+97|53\n61|29\n47|13\n75|47\n97|75\n47|61\n75|61\n47|29\n75|13\n53|13\n\n\
+75,47,61,53,29\n97,61,53,29,13\n75,29,13\n75,97,47,61,53\n61,13,29\n97,13,75,29,47\n";
+
+    #[test]
+    fn sample_summary() {
+        let (pairs, updates) = parse_text_u32(SAMPLE).unwrap();
+        for choice in [OrderChoice::InputOrder, OrderChoice::Smallest, OrderChoice::Largest] {
+            let summary = summarize(&pairs, &updates, choice);
+            assert_eq!(summary, UpdateSummary {
+                valid_middle_sum: 143,
+                reordered_middle_sum: 123,
+                valid_count: 3,
+                fixed_count: 3,
+                unfixable_count: 0,
+            });
+        }
+    }
+
+    #[test]
+    fn rule_edits_update_the_sample_counts() {
+        let (pairs, updates) = parse_text_u32(SAMPLE).unwrap();
+        assert_eq!(apply_rule_edits(&pairs, &updates, &[]), 3);
+        // 97|75 is the only rule the fourth update breaks
+        assert_eq!(apply_rule_edits(&pairs, &updates, &[RuleEdit::Remove(97, 75)]), 4);
+        // 13|75 would close a cycle through 75|13, so it is rejected and nothing changes
+        assert_eq!(apply_rule_edits(&pairs, &updates, &[RuleEdit::Remove(97, 75), RuleEdit::Add(13, 75)]), 4);
+        assert_eq!(apply_rule_edits(&pairs, &updates, &[RuleEdit::Remove(97, 75), RuleEdit::Add(97, 75)]), 3);
+    }
+}
@@ -28,6 +28,17 @@ impl Direction {
             Direction::Left => (-1, 0),
         }
     }
+
+    fn index(&self) -> usize {
+        match self {
+            Direction::Up => 0,
+            Direction::Right => 1,
+            Direction::Down => 2,
+            Direction::Left => 3,
+        }
+    }
+
+    const ALL: [Direction; 4] = [Direction::Up, Direction::Right, Direction::Down, Direction::Left];
 }
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
@@ -37,6 +48,71 @@ struct GuardState {
     direction: Direction,
 }
 
+// Where a guard eventually ends up: walking off the map, or stuck in one of the cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Outcome {
+    Exit,
+    Cycle(usize),
+}
+
+// State transition graph of the guard's walk. Each node is a "turn state": the guard has
+// just turned right in front of an obstruction. Every node has at most one successor (the
+// next turn state), so the graph is a set of paths that either leave the map or end in a cycle.
+#[derive(Debug, Clone)]
+struct TransitionGraph {
+    nodes: Vec<GuardState>,
+    // first_turn[(y * width + x) * 4 + direction] is the first turn state reached from that state
+    first_turn: Vec<Option<usize>>,
+    cycles: Vec<Vec<usize>>,
+    outcome: Vec<Outcome>,
+    width: usize,
+}
+
+impl TransitionGraph {
+    fn cycles(&self) -> Vec<Vec<GuardState>> {
+        self.cycles
+            .iter()
+            .map(|cycle| cycle.iter().map(|&node| self.nodes[node]).collect())
+            .collect()
+    }
+
+    fn exit_states(&self) -> Vec<GuardState> {
+        self.nodes
+            .iter()
+            .zip(&self.outcome)
+            .filter(|(_, &outcome)| outcome == Outcome::Exit)
+            .map(|(&state, _)| state)
+            .collect()
+    }
+
+    fn loop_states(&self) -> Vec<GuardState> {
+        self.nodes
+            .iter()
+            .zip(&self.outcome)
+            .filter(|(_, &outcome)| outcome != Outcome::Exit)
+            .map(|(&state, _)| state)
+            .collect()
+    }
+
+    // All turn states that end up in the given cycle, including the cycle itself
+    fn basin(&self, cycle: usize) -> Vec<GuardState> {
+        self.nodes
+            .iter()
+            .zip(&self.outcome)
+            .filter(|(_, &outcome)| outcome == Outcome::Cycle(cycle))
+            .map(|(&state, _)| state)
+            .collect()
+    }
+
+    fn outcome_from(&self, state: GuardState) -> Outcome {
+        let slot = (state.y * self.width + state.x) * 4 + state.direction.index();
+        match self.first_turn[slot] {
+            Some(node) => self.outcome[node],
+            None => Outcome::Exit,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Map {
     grid: Vec<Vec<char>>,
@@ -49,11 +125,15 @@ impl Map {
     fn from_file(path: &str) -> io::Result<Self> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
+        let lines = reader.lines().collect::<io::Result<Vec<String>>>()?;
+        Map::from_lines(&lines)
+    }
+
+    fn from_lines(lines: &[String]) -> io::Result<Self> {
         let mut grid = Vec::new();
         let mut guard = None;
 
-        for (y, line) in reader.lines().enumerate() {
-            let line = line?;
+        for (y, line) in lines.iter().enumerate() {
             let mut row = Vec::new();
 
             for (x, ch) in line.chars().enumerate() {
@@ -90,15 +170,6 @@ impl Map {
         })
     }
 
-    fn is_obstruction(&self, x: isize, y: isize) -> bool {
-        if x < 0 || y < 0 ||
-            x >= self.width as isize ||
-            y >= self.height as isize {
-            return true;
-        }
-        self.grid[y as usize][x as usize] == '#'
-    }
-
     fn simulate_with_extra_obstruction(&self, obs_x: usize, obs_y: usize) -> bool {
         // Don't place obstruction at guard's starting position
         if obs_x == self.initial_guard.x && obs_y == self.initial_guard.y {
@@ -161,22 +232,263 @@ impl Map {
         }
 
         // Print the positions that create loops
-        println!("\nPositions that create loops:");
+        verbose!("\nPositions that create loops:");
         for (x, y) in loop_positions {
-            println!("Position ({}, {})", x, y);
+            verbose!("Position ({}, {})", x, y);
         }
 
         count
     }
+
+    fn is_open(&self, x: isize, y: isize) -> Option<bool> {
+        if x < 0 || y < 0 ||
+            x >= self.width as isize ||
+            y >= self.height as isize {
+            return None;
+        }
+        Some(self.grid[y as usize][x as usize] != '#')
+    }
+
+    fn transition_graph(&self) -> TransitionGraph {
+        // Every turn state: an open cell with an obstruction straight ahead, after turning right
+        let mut nodes = Vec::new();
+        let mut index = HashMap::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.grid[y][x] == '#' {
+                    continue;
+                }
+                for direction in Direction::ALL {
+                    let (dx, dy) = direction.get_movement();
+                    if self.is_open(x as isize + dx, y as isize + dy) == Some(false) {
+                        let state = GuardState { x, y, direction: direction.turn_right() };
+                        index.insert(state, nodes.len());
+                        nodes.push(state);
+                    }
+                }
+            }
+        }
+
+        // First turn state reachable from every (cell, direction). Cells are visited so that
+        // the cell ahead is always filled in before the current one.
+        let mut first_turn = vec![None; self.width * self.height * 4];
+        for direction in Direction::ALL {
+            let (dx, dy) = direction.get_movement();
+            let ys: Vec<usize> = if dy > 0 { (0..self.height).rev().collect() } else { (0..self.height).collect() };
+            let xs: Vec<usize> = if dx > 0 { (0..self.width).rev().collect() } else { (0..self.width).collect() };
+
+            for &y in &ys {
+                for &x in &xs {
+                    if self.grid[y][x] == '#' {
+                        continue;
+                    }
+                    let next_x = x as isize + dx;
+                    let next_y = y as isize + dy;
+                    let slot = (y * self.width + x) * 4 + direction.index();
+                    first_turn[slot] = match self.is_open(next_x, next_y) {
+                        None => None,
+                        Some(false) => Some(index[&GuardState { x, y, direction: direction.turn_right() }]),
+                        Some(true) => {
+                            first_turn[(next_y as usize * self.width + next_x as usize) * 4 + direction.index()]
+                        }
+                    };
+                }
+            }
+        }
+
+        // next[node] is the next turn state, or None if the guard walks off the map first
+        let next: Vec<Option<usize>> = nodes
+            .iter()
+            .map(|state| first_turn[(state.y * self.width + state.x) * 4 + state.direction.index()])
+            .collect();
+
+        // Walk each unvisited path until it exits, hits a known node, or closes a cycle
+        const UNVISITED: u8 = 0;
+        const ON_PATH: u8 = 1;
+        const DONE: u8 = 2;
+        let mut status = vec![UNVISITED; nodes.len()];
+        let mut outcome = vec![Outcome::Exit; nodes.len()];
+        let mut cycles = Vec::new();
+
+        for start in 0..nodes.len() {
+            if status[start] != UNVISITED {
+                continue;
+            }
+
+            let mut path = Vec::new();
+            let mut current = Some(start);
+            let path_outcome = loop {
+                match current {
+                    None => break Outcome::Exit,
+                    Some(node) if status[node] == DONE => break outcome[node],
+                    Some(node) if status[node] == ON_PATH => {
+                        // Found a new cycle: everything on the path from `node` onwards
+                        let cycle_start = path.iter().position(|&n| n == node).unwrap();
+                        let cycle_id = cycles.len();
+                        cycles.push(path[cycle_start..].to_vec());
+                        break Outcome::Cycle(cycle_id);
+                    }
+                    Some(node) => {
+                        status[node] = ON_PATH;
+                        path.push(node);
+                        current = next[node];
+                    }
+                }
+            };
+
+            for node in path {
+                status[node] = DONE;
+                outcome[node] = path_outcome;
+            }
+        }
+
+        TransitionGraph {
+            nodes,
+            first_turn,
+            cycles,
+            outcome,
+            width: self.width,
+        }
+    }
+
+    // For every open cell and starting direction, whether the guard escapes the map
+    fn escape_table(&self, graph: &TransitionGraph) -> HashMap<GuardState, bool> {
+        let mut table = HashMap::new();
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.grid[y][x] == '#' {
+                    continue;
+                }
+                for direction in Direction::ALL {
+                    let state = GuardState { x, y, direction };
+                    table.insert(state, graph.outcome_from(state) == Outcome::Exit);
+                }
+            }
+        }
+
+        table
+    }
 }
 
 pub fn process(input_file_path: &str) -> io::Result<()> {
     let mut map = Map::from_file(input_file_path)?;
 
-    println!("Analyzing possible obstruction positions...");
+    let graph = map.transition_graph();
+    info!("Turn states: {}, cycles: {}, leading to exit: {}, leading to a loop: {}",
+          graph.nodes.len(), graph.cycles.len(), graph.exit_states().len(), graph.loop_states().len());
+    info!("Guard starting position escapes: {}", graph.outcome_from(map.initial_guard) == Outcome::Exit);
+
+    let table = map.escape_table(&graph);
+    let escaping = table.values().filter(|&&escapes| escapes).count();
+    info!("Start states that escape: {} of {}", escaping, table.len());
+    for (cycle_id, cycle) in graph.cycles().iter().enumerate() {
+        verbose!("Cycle {} ({} turns) is reached from {} turn states", cycle_id, cycle.len(), graph.basin(cycle_id).len());
+    }
+    let mut trapped: Vec<&GuardState> = table.iter().filter(|(_, &escapes)| !escapes).map(|(state, _)| state).collect();
+    trapped.sort_by_key(|state| (state.y, state.x, state.direction.index()));
+    for state in trapped {
+        trace!("Trapped: ({}, {}) facing {:?}", state.x, state.y, state.direction);
+    }
+
+    info!("Analyzing possible obstruction positions...");
 
     let loop_count = map.count_possible_loop_obstructions();
-    println!("\nFound {} positions where adding an obstruction creates a loop", loop_count);
+    info!("");
+    println!("Found {} positions where adding an obstruction creates a loop", loop_count);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Walks the guard step by step, the slow way
+    fn escapes_by_simulation(map: &Map, start: GuardState) -> bool {
+        let mut guard = start;
+        let mut visited = HashSet::new();
+        while visited.insert(guard) {
+            let (dx, dy) = guard.direction.get_movement();
+            match map.is_open(guard.x as isize + dx, guard.y as isize + dy) {
+                None => return true,
+                Some(false) => guard.direction = guard.direction.turn_right(),
+                Some(true) => {
+                    guard.x = (guard.x as isize + dx) as usize;
+                    guard.y = (guard.y as isize + dy) as usize;
+                }
+            }
+        }
+        false
+    }
+
+    fn random_map(seed: u64, width: usize, height: usize) -> Map {
+        let mut state = seed;
+        let mut lines = Vec::new();
+        for y in 0..height {
+            let line: String = (0..width)
+                .map(|x| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    if (x, y) == (0, 0) {
+                        '^'
+                    } else if (state >> 33).is_multiple_of(5) {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            lines.push(line);
+        }
+        Map::from_lines(&lines).unwrap()
+    }
+
+    fn sample_map() -> Map {
+        let lines: Vec<String> = [
+            "....#.....",
+            ".........#",
+            "..........",
+            "..#.......",
+            ".......#..",
+            "..........",
+            ".#..^.....",
+            "........#.",
+            "#.........",
+            "......#...",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+        Map::from_lines(&lines).unwrap()
+    }
+
+    #[test]
+    fn escape_table_matches_simulation() {
+        let maps = std::iter::once(sample_map()).chain((1..=50).map(|seed| random_map(seed, 9, 7)));
+        for map in maps {
+            for (state, escapes) in map.escape_table(&map.transition_graph()) {
+                assert_eq!(escapes, escapes_by_simulation(&map, state), "state {:?}", state);
+            }
+        }
+    }
+
+    #[test]
+    fn basins_cover_every_looping_turn_state() {
+        for seed in 1..=50 {
+            let graph = random_map(seed, 9, 7).transition_graph();
+            let in_basins: usize = (0..graph.cycles().len()).map(|cycle| graph.basin(cycle).len()).sum();
+            assert_eq!(in_basins, graph.loop_states().len());
+            for (cycle_id, cycle) in graph.cycles().iter().enumerate() {
+                let basin = graph.basin(cycle_id);
+                assert!(cycle.iter().all(|state| basin.contains(state)));
+            }
+        }
+    }
+
+    #[test]
+    fn sample_guard_escapes() {
+        let map = sample_map();
+        assert!(map.escape_table(&map.transition_graph())[&map.initial_guard]);
+        assert_eq!(map.count_possible_loop_obstructions(), 6);
+    }
+}
@@ -101,13 +101,13 @@ pub fn process(input_file_path: &str) -> io::Result<()> {
             Ok((results, inputs)) => {
                 let target = results[0];
                 if is_valid_combination(&inputs, target) {
-                    println!("Valid combination found for inputs {:?} to make {}", inputs, target);
+                    verbose!("Valid combination found for inputs {:?} to make {}", inputs, target);
                     sum += target;
                 } else {
-                    println!("No valid combination found for inputs {:?} to make {}", inputs, target);
+                    verbose!("No valid combination found for inputs {:?} to make {}", inputs, target);
                 }
             }
-            Err(e) => println!("Error parsing string: {}", e),
+            Err(e) => info!("Error parsing string: {}", e),
         }
     }
 
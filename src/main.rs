@@ -1,3 +1,8 @@
+use std::env;
+use std::process;
+
+#[macro_use]
+mod output;
 mod day1;
 mod day2;
 mod day3;
@@ -6,6 +11,198 @@ mod day5;
 mod day6;
 mod day7;
 
+struct Options {
+    day: u32,
+    input: String,
+    dot_output: Option<String>,
+    mermaid_output: Option<String>,
+    update_number: Option<usize>,
+    rule_edits: Vec<day5::RuleEdit<u32>>,
+    order: day5::OrderChoice,
+    instructions: day3::InstructionSet,
+    lex: day3::LexOptions,
+    highlight: bool,
+    words: Option<String>,
+    pattern: Option<String>,
+    report: day4::ReportOptions,
+    generate: Option<day4::PuzzleSpec>,
+    verbosity: output::Verbosity,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: Advent [day] [input file] [--dot FILE] [--mermaid FILE] [--update N] [--add-rule A|B] [--remove-rule A|B] [--order input|smallest|largest] [--instructions standard|extended] [--max-digits N|any] [--highlight] [--words FILE] [--pattern SHAPE] [--render dots|colour] [--list] [--overlap every|disjoint|palindromes] [-i | --ignore-case] [--wrap] [--generate HxW] [--directions LIST] [--seed N] [-q | -v | --trace]");
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut positional = Vec::new();
+    let mut dot_output = None;
+    let mut mermaid_output = None;
+    let mut update_number = None;
+    let mut rule_edits = Vec::new();
+    let mut order = day5::OrderChoice::InputOrder;
+    let mut instructions = day3::InstructionSet::standard();
+    let mut lex = day3::LexOptions::default();
+    let mut highlight = false;
+    let mut words = None;
+    let mut pattern = None;
+    let mut report = day4::ReportOptions::default();
+    let mut generate = false;
+    let mut spec = day4::PuzzleSpec::default();
+    let mut verbosity = output::Verbosity::Normal;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dot" => dot_output = Some(args.next().unwrap_or_else(|| usage())),
+            "--mermaid" => mermaid_output = Some(args.next().unwrap_or_else(|| usage())),
+            "--update" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                // Updates are numbered from 1
+                update_number = Some(value.parse().ok().filter(|&number| number > 0).unwrap_or_else(|| usage()));
+            }
+            "--add-rule" | "--remove-rule" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                let (before, after) = value.split_once('|').unwrap_or_else(|| usage());
+                let before = before.parse().unwrap_or_else(|_| usage());
+                let after = after.parse().unwrap_or_else(|_| usage());
+                rule_edits.push(if arg == "--add-rule" {
+                    day5::RuleEdit::Add(before, after)
+                } else {
+                    day5::RuleEdit::Remove(before, after)
+                });
+            }
+            "--order" => {
+                order = match args.next().as_deref() {
+                    Some("input") => day5::OrderChoice::InputOrder,
+                    Some("smallest") => day5::OrderChoice::Smallest,
+                    Some("largest") => day5::OrderChoice::Largest,
+                    _ => usage(),
+                };
+            }
+            "--instructions" => {
+                instructions = match args.next().as_deref() {
+                    Some("standard") => day3::InstructionSet::standard(),
+                    Some("extended") => day3::InstructionSet::extended(),
+                    _ => usage(),
+                };
+            }
+            "--max-digits" => {
+                lex.max_digits = match args.next().as_deref() {
+                    Some("any") => None,
+                    Some(value) => Some(value.parse().unwrap_or_else(|_| usage())),
+                    None => usage(),
+                };
+            }
+            "--highlight" => highlight = true,
+            "--words" => words = Some(args.next().unwrap_or_else(|| usage())),
+            "--pattern" => pattern = Some(args.next().unwrap_or_else(|| usage())),
+            "--render" => {
+                report.render = match args.next().as_deref() {
+                    Some("dots") => Some(day4::RenderStyle::MatchedOnly),
+                    Some("colour") | Some("color") => Some(day4::RenderStyle::Colour),
+                    _ => usage(),
+                };
+            }
+            "--list" => report.list_matches = true,
+            "-i" | "--ignore-case" => report.matching.case_insensitive = true,
+            "--wrap" => report.matching.wrap_around = true,
+            "--overlap" => {
+                report.overlap = match args.next().map(|policy| policy.parse()) {
+                    Some(Ok(policy)) => policy,
+                    _ => usage(),
+                };
+            }
+            "--generate" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                let (height, width) = value.split_once('x').unwrap_or_else(|| usage());
+                spec.height = height.parse().unwrap_or_else(|_| usage());
+                spec.width = width.parse().unwrap_or_else(|_| usage());
+                generate = true;
+            }
+            "--directions" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                spec.directions = value.split(',').map(|name| name.parse()).collect::<Result<_, _>>().unwrap_or_else(|_| usage());
+            }
+            "--seed" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                spec.seed = value.parse().unwrap_or_else(|_| usage());
+            }
+            "-q" | "--quiet" => verbosity = output::Verbosity::Quiet,
+            "-v" | "--verbose" => verbosity = output::Verbosity::Verbose,
+            "-vv" | "--trace" => verbosity = output::Verbosity::Trace,
+            _ if arg.starts_with('-') => usage(),
+            _ => positional.push(arg),
+        }
+    }
+
+    // Defaults to today's puzzle and the usual `dayN.txt` input
+    let day = match positional.first() {
+        Some(day) => day.trim_start_matches("day").parse().unwrap_or_else(|_| usage()),
+        None => 7,
+    };
+    let input = positional.get(1).cloned().unwrap_or_else(|| format!("day{}.txt", day));
+    // Generating needs the words to place
+    if generate && words.is_none() {
+        usage();
+    }
+
+    Options {
+        day,
+        input,
+        dot_output,
+        mermaid_output,
+        update_number,
+        rule_edits,
+        order,
+        instructions,
+        lex,
+        highlight,
+        words,
+        pattern,
+        report,
+        generate: generate.then_some(spec),
+        verbosity,
+    }
+}
+
+fn run_day5(options: &Options) -> Result<(), day5::ParseError> {
+    let (pairs, updates) = day5::parse_file::<u32>(&options.input)?;
+    day5::process(&pairs, &updates, options.order);
+    if !options.rule_edits.is_empty() {
+        day5::apply_rule_edits(&pairs, &updates, &options.rule_edits);
+    }
+
+    if let Some(path) = &options.dot_output {
+        day5::export_graph(&pairs, &updates, day5::GraphFormat::Dot, options.update_number, path)?;
+        info!("Wrote DOT graph to {}", path);
+    }
+    if let Some(path) = &options.mermaid_output {
+        day5::export_graph(&pairs, &updates, day5::GraphFormat::Mermaid, options.update_number, path)?;
+        info!("Wrote Mermaid graph to {}", path);
+    }
+    Ok(())
+}
+
 fn main() {
-    day7::process("day7.txt").expect("Failed");
+    let options = parse_args();
+    output::set_verbosity(options.verbosity);
+
+    match options.day {
+        1 => day1::process(&options.input),
+        2 => day2::process(&options.input),
+        3 => day3::process(&options.input, &options.instructions, &options.lex, options.highlight).expect("Failed"),
+        4 => match (&options.words, &options.pattern) {
+            (Some(dictionary), _) if options.generate.is_some() => {
+                day4::process_generate(dictionary, options.generate.as_ref().unwrap())
+            }
+            (Some(dictionary), _) => day4::process_dictionary(&options.input, dictionary, &options.report),
+            (None, Some(pattern)) => day4::process_pattern(&options.input, pattern, &options.report),
+            (None, None) => day4::process(&options.input, &options.report),
+        },
+        5 => run_day5(&options).expect("Failed"),
+        6 => day6::process(&options.input).expect("Failed"),
+        7 => day7::process(&options.input).expect("Failed"),
+        _ => usage(),
+    }
 }
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// How much the puzzles print. Answers are always printed; everything else goes through the
+// macros below so the runner can turn it up or down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    // Only the answers
+    Quiet = 0,
+    // Answers plus summaries and warnings
+    Normal = 1,
+    // Per-item traces (every update, every line, ...)
+    Verbose = 2,
+    // Internal data structures
+    Trace = 3,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(level: Verbosity) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Verbosity) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::output::enabled($crate::output::Verbosity::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::output::enabled($crate::output::Verbosity::Verbose) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::output::enabled($crate::output::Verbosity::Trace) {
+            println!($($arg)*);
+        }
+    };
+}